@@ -0,0 +1,476 @@
+use serde_json::{json, Value};
+use crate::response::{Response, ResponseCode, ResponseBody};
+use crate::request::{Request, RequestBody, Method};
+use crate::database::{Database, DbError, UrlStats, ApiKey, UrlChange};
+use crate::auth::{self, AuthError, Principal, Scope};
+use crate::handler::{HandlerError, CreateError, LinkOptions, create_short_url, normalize_long_url, parse_expires_at,
+                     parse_max_redirects, parse_redirect_code, rate_limit_client};
+use crate::ratelimit::{self, Action};
+use crate::config::config;
+use crate::log;
+use chrono::{Local, Duration};
+
+// Versioned json api for scripts and bots, all endpoints need `Authorization: Bearer <api key or password>`
+// with the scope in brackets:
+//
+// - `POST   /api/v1/links`          `{"long": "...", "short": "...", "expires_at": "...", "max_redirects": 1,
+//                                   "passphrase": "...", "redirect_code": 302}` (only long is required)
+//                                   -> 201 + link (create), 429 if the key (or ip for passwords) creates too fast
+// - `GET    /api/v1/links/<short>`  -> 200 + link (read-stats)
+// - `PATCH  /api/v1/links/<short>`  `{"long": "..."}` -> 200 + link (create)
+// - `GET    /api/v1/links/<short>/history` -> 200 + list of target changes, newest first (read-stats)
+// - `GET    /api/v1/links/<short>/clicks?days=7` -> 200 + logged clicks per day / hour, the top referrers
+//                                   and unique visitors per day of the last days (at most 365, default 7) (read-stats)
+// - `POST   /api/v1/links/<short>/rollback` `{"id": 3}` -> 200 + link with the target from before
+//                                   change 3, without id before the latest change (create)
+// - `DELETE /api/v1/links/<short>`  -> 204 (delete)
+// - `POST   /api/v1/keys`           `{"name": "...", "scopes": [...], "quota": 10}` -> 201 + key with secret (admin)
+// - `GET    /api/v1/keys`           -> 200 + list of keys (admin)
+// - `DELETE /api/v1/keys/<id>`      -> 204, revokes the key (admin)
+//
+// Errors are answered with `{"error": {"code": "<machine readable>", "message": "<human readable>"}}`
+
+
+fn json_response(code: ResponseCode, value: Value) -> Response {
+    Response {
+        code,
+        custom_headers: None,
+        body: ResponseBody::Json(value.to_string())
+    }
+}
+
+/// json error with a machine readable `code`
+fn api_error(status: ResponseCode, code: &str, message: &str) -> HandlerError {
+    HandlerError::Custom(json_response(status, json!({
+        "error": {
+            "code": code,
+            "message": message
+        }
+    })))
+}
+
+/// `429` with `Retry-After`
+fn rate_limited(retry_after: u64) -> HandlerError {
+    let mut r = json_response(ResponseCode::TooManyRequests, json!({
+        "error": { "code": "rate_limited", "message": "Too many requests, try again later" }
+    }));
+    r.set_header("Retry-After", &retry_after.to_string());
+    HandlerError::Custom(r)
+}
+
+fn db_error(e: DbError) -> HandlerError {
+    match e {
+        DbError::NotFound => api_error(ResponseCode::NotFound, "not_found", "Short URL does not exist"),
+        DbError::Expired => api_error(ResponseCode::Gone, "expired", "Short URL has expired"),
+        DbError::Conflict => api_error(ResponseCode::Conflict, "conflict", "Already exists"),
+        DbError::Busy => {
+            let mut r = json_response(ResponseCode::ServiceUnavailable, json!({
+                "error": { "code": "db_busy", "message": "Database is busy, try again" }
+            }));
+            r.set_header("Retry-After", "1");
+            HandlerError::Custom(r)
+        },
+        DbError::Corrupt | DbError::Other(_) => {
+            log(format!("Database failed: {}", e));
+            api_error(ResponseCode::InternalServerError, "db_error", "Database failed")
+        }
+    }
+}
+
+fn link_json(short: &str, stats: &UrlStats) -> Value {
+    json!({
+        "short": short,
+        "url": format!("{}/{}", config().base_url, short),
+        "long": stats.long,
+        "created": stats.created,
+        "redirects": stats.redirects,
+        "bot_redirects": stats.bot_redirects,
+        "unique_visitors": stats.unique_visitors,
+        "last_redirect": if stats.redirects > 0 { Some(&stats.last_redirect) } else { None },
+        "expires_at": stats.expires_at,
+        "max_redirects": stats.max_redirects,
+        "protected": stats.protected,
+        "redirect_code": stats.redirect_code
+    })
+}
+
+fn change_json(change: &UrlChange) -> Value {
+    json!({
+        "id": change.id,
+        "old_long": change.old_long,
+        "new_long": change.new_long,
+        "changed": change.changed,
+        "actor": change.actor
+    })
+}
+
+fn key_json(key: &ApiKey) -> Value {
+    json!({
+        "id": key.id,
+        "name": key.name,
+        "scopes": key.scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+        "quota": key.quota,
+        "created": key.created,
+        "last_used": key.last_used,
+        "revoked": key.revoked
+    })
+}
+
+/// checks the `Authorization: Bearer <api key or password>` header
+fn authorize(req: &Request, db: &dyn Database) -> Result<Principal, HandlerError> {
+    let principal = match auth::bearer_token(req) {
+        Some(t) => auth::authenticate(req, db, t).map_err(|e| match e {
+            AuthError::TooManyFailures(retry_after) => rate_limited(retry_after),
+            AuthError::Db(e) => db_error(e)
+        })?,
+        None => None
+    };
+    if let Some(p) = principal {
+        return Ok(p);
+    }
+    let mut r = json_response(ResponseCode::Unauthorized, json!({
+        "error": { "code": "unauthorized", "message": "Missing or invalid bearer token" }
+    }));
+    r.set_header("WWW-Authenticate", "Bearer");
+    Err(HandlerError::Custom(r))
+}
+
+/// the json object of the request body
+fn json_body(req: &Request) -> Result<&serde_json::Map<String, Value>, HandlerError> {
+    match &req.body {
+        Some(RequestBody::Json(Value::Object(map))) => Ok(map),
+        _ => Err(api_error(ResponseCode::BadRequest, "invalid_body", "Expected a json object as body"))
+    }
+}
+
+/// the normalized `long` field of the body
+fn long_url_field(body: &serde_json::Map<String, Value>) -> Result<String, HandlerError> {
+    let long = body.get("long").and_then(|l| l.as_str())
+        .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_body", "Field long is missing"))?;
+    normalize_long_url(long)
+        .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_long_url", "This is no valid url to store"))
+}
+
+/// an optional json number, strings like `"2"` and fractions fail with the error `code`
+fn integer_field(body: &serde_json::Map<String, Value>, field: &str, code: &str) -> Result<Option<u64>, HandlerError> {
+    match body.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => v.as_u64().map(Some)
+            .ok_or_else(|| api_error(ResponseCode::BadRequest, code, &format!("Field {} must be a positive integer", field)))
+    }
+}
+
+fn require(principal: &Principal, scope: Scope) -> Result<(), HandlerError> {
+    if principal.allows(scope) {
+        Ok(())
+    } else {
+        Err(api_error(ResponseCode::Forbidden, "forbidden", &format!("This key needs the {} scope", scope.as_str())))
+    }
+}
+
+/// Router of `/api/v1/...`
+pub fn handle_v1(req: &Request, db: &dyn Database) -> Result<Response, HandlerError> {
+    let p = authorize(req, db)?;
+
+    match (&req.url[2..], &req.method) {
+        ([links], Method::Post) if links == "links" => { require(&p, Scope::Create)?; create_link(req, &p, db) },
+        ([links, short], Method::Get) if links == "links" => { require(&p, Scope::ReadStats)?; get_link(short, db) },
+        ([links, short], Method::Patch) if links == "links" => { require(&p, Scope::Create)?; update_link(req, &p, short, db) },
+        ([links, short], Method::Delete) if links == "links" => { require(&p, Scope::Delete)?; delete_link(short, db) },
+        ([links, short, history], Method::Get) if links == "links" && history == "history" =>
+            { require(&p, Scope::ReadStats)?; link_history(short, db) },
+        ([links, short, clicks], Method::Get) if links == "links" && clicks == "clicks" =>
+            { require(&p, Scope::ReadStats)?; link_clicks(req, short, db) },
+        ([links, short, rollback], Method::Post) if links == "links" && rollback == "rollback" =>
+            { require(&p, Scope::Create)?; rollback_link(req, &p, short, db) },
+        ([links], _) | ([links, _], _) | ([links, _, _], _) if links == "links" =>
+            Err(api_error(ResponseCode::MethodNotAllowed, "method_not_allowed", "Method not allowed on this endpoint")),
+        ([keys], Method::Post) if keys == "keys" => { require(&p, Scope::Admin)?; create_key(req, db) },
+        ([keys], Method::Get) if keys == "keys" => { require(&p, Scope::Admin)?; list_keys(db) },
+        ([keys, id], Method::Delete) if keys == "keys" => { require(&p, Scope::Admin)?; revoke_key(id, db) },
+        ([keys], _) | ([keys, _], _) if keys == "keys" =>
+            Err(api_error(ResponseCode::MethodNotAllowed, "method_not_allowed", "Method not allowed on this endpoint")),
+        _ => Err(api_error(ResponseCode::NotFound, "unknown_endpoint", "Unknown api endpoint"))
+    }
+}
+
+fn create_link(req: &Request, principal: &Principal, db: &dyn Database) -> Result<Response, HandlerError> {
+    ratelimit::check(Action::Create, &rate_limit_client(req, Some(principal))).map_err(rate_limited)?;
+    let body = json_body(req)?;
+    let long = long_url_field(body)?;
+    let short = match body.get("short") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(s)) => Some(s.as_str()),
+        Some(_) => return Err(api_error(ResponseCode::BadRequest, "invalid_body", "Field short must be a string"))
+    };
+    let options = LinkOptions {
+        expires_at: match body.get("expires_at") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(e)) => Some(parse_expires_at(e)
+                .map_err(|e| api_error(ResponseCode::BadRequest, "invalid_expires_at", &e))?),
+            Some(_) => return Err(api_error(ResponseCode::BadRequest, "invalid_body", "Field expires_at must be a string"))
+        },
+        max_redirects: match integer_field(body, "max_redirects", "invalid_max_redirects")? {
+            None => None,
+            Some(m) => Some(parse_max_redirects(&m.to_string())
+                .map_err(|e| api_error(ResponseCode::BadRequest, "invalid_max_redirects", &e))?)
+        },
+        passphrase: match body.get("passphrase") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(p)) if !p.is_empty() => Some(p.clone()),
+            Some(_) => return Err(api_error(ResponseCode::BadRequest, "invalid_body", "Field passphrase must be a non-empty string"))
+        },
+        redirect_code: match integer_field(body, "redirect_code", "invalid_redirect_code")? {
+            None => None,
+            Some(c) => Some(parse_redirect_code(&c.to_string())
+                .map_err(|e| api_error(ResponseCode::BadRequest, "invalid_redirect_code", &e))?)
+        }
+    };
+
+    let short = create_short_url(db, &long, short, &options, &req.ip, principal.api_key()).map_err(|e| match e {
+        CreateError::Taken(s) =>
+            api_error(ResponseCode::Conflict, "short_url_taken", &format!("Short URL {} already exists", s)),
+        CreateError::NotAllowed(s) =>
+            api_error(ResponseCode::BadRequest, "invalid_short_url", &format!("Short URL {} is not allowed", s)),
+        CreateError::QuotaExceeded(n) =>
+            api_error(ResponseCode::TooManyRequests, "quota_exceeded", &format!("{} URLs created in the last 7 days", n)),
+        CreateError::Db(e) => db_error(e)
+    })?;
+
+    let stats = db.url_stats(&short).map_err(db_error)?;
+    let mut r = json_response(ResponseCode::Created, link_json(&short, &stats));
+    r.set_header("Location", &format!("{}/api/v1/links/{}", config().base_url, short));
+    Ok(r)
+}
+
+fn get_link(short: &str, db: &dyn Database) -> Result<Response, HandlerError> {
+    let stats = db.url_stats(short).map_err(db_error)?;
+    Ok(json_response(ResponseCode::Ok, link_json(short, &stats)))
+}
+
+fn update_link(req: &Request, principal: &Principal, short: &str, db: &dyn Database) -> Result<Response, HandlerError> {
+    let long = long_url_field(json_body(req)?)?;
+    log(format!("Updating {} -> {}", short, long));
+    db.update_long_url(short, &long, &principal.actor()).map_err(db_error)?;
+    get_link(short, db)
+}
+
+fn link_history(short: &str, db: &dyn Database) -> Result<Response, HandlerError> {
+    // 404 for unknown urls instead of an empty list
+    db.url_stats(short).map_err(db_error)?;
+    let history = db.url_history(short).map_err(db_error)?;
+    Ok(json_response(ResponseCode::Ok, Value::Array(history.iter().map(change_json).collect())))
+}
+
+fn link_clicks(req: &Request, short: &str, db: &dyn Database) -> Result<Response, HandlerError> {
+    let days: i64 = match req.params.get("days") {
+        None => 7,
+        Some(d) => d.parse().ok().filter(|d| (1..=365).contains(d))
+            .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_days", "Parameter days must be between 1 and 365"))?
+    };
+    let stats = db.url_stats(short).map_err(db_error)?;
+    // clicks from before `created` belong to a deleted or expired url with the same name
+    let since = (Local::now().naive_local() - Duration::days(days)).format("%Y-%m-%d %H:%M:%S").to_string()
+        .max(stats.created);
+    let clicks = db.click_stats(short, &since).map_err(db_error)?;
+    let visitors = db.daily_unique_visitors(short, &since[..10]).map_err(db_error)?;
+    let counts = |counts: &[(String, u32)], key: &str| -> Value {
+        counts.iter().map(|(k, n)| json!({ key: k, "clicks": n })).collect()
+    };
+    Ok(json_response(ResponseCode::Ok, json!({
+        "short": short,
+        "since": since,
+        "daily": counts(&clicks.daily, "day"),
+        "hourly": counts(&clicks.hourly, "hour"),
+        "top_referrers": counts(&clicks.top_referrers, "referer"),
+        "daily_unique_visitors": visitors.iter().map(|(day, n)| json!({ "day": day, "visitors": n })).collect::<Value>()
+    })))
+}
+
+/// sets the target back to the one before a change, which is recorded as a new change
+fn rollback_link(req: &Request, principal: &Principal, short: &str, db: &dyn Database) -> Result<Response, HandlerError> {
+    let id = match &req.body {
+        None => None,
+        Some(_) => match json_body(req)?.get("id") {
+            None | Some(Value::Null) => None,
+            Some(id) => Some(id.as_i64()
+                .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_body", "Field id must be a number"))?)
+        }
+    };
+    db.url_stats(short).map_err(db_error)?;
+    let history = db.url_history(short).map_err(db_error)?;
+    let change = match id {
+        Some(id) => history.iter().find(|c| c.id == id),
+        None => history.first()
+    }.ok_or_else(|| api_error(ResponseCode::NotFound, "not_found", "No such change of this short URL"))?;
+
+    log(format!("Rolling {} back to {}", short, change.old_long));
+    db.update_long_url(short, &change.old_long, &principal.actor()).map_err(db_error)?;
+    get_link(short, db)
+}
+
+fn delete_link(short: &str, db: &dyn Database) -> Result<Response, HandlerError> {
+    log(format!("Deleting {}", short));
+    db.delete_shortened(short).map_err(db_error)?;
+    Ok(Response {
+        code: ResponseCode::NoContent,
+        custom_headers: None,
+        body: ResponseBody::Empty
+    })
+}
+
+fn create_key(req: &Request, db: &dyn Database) -> Result<Response, HandlerError> {
+    let body = json_body(req)?;
+    let name = body.get("name").and_then(|n| n.as_str())
+        .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_body", "Field name is missing"))?;
+    let scopes = body.get("scopes").and_then(|s| s.as_array())
+        .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_body", "Field scopes is missing"))?
+        .iter()
+        .map(|s| s.as_str().and_then(Scope::parse)
+            .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_scope",
+                                     "Scopes are create, delete, read-stats and admin")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let quota = match body.get("quota") {
+        None | Some(Value::Null) => None,
+        Some(q) => Some(q.as_u64().filter(|q| *q <= u32::MAX as u64)
+            .ok_or_else(|| api_error(ResponseCode::BadRequest, "invalid_body", "Field quota must be a positive number"))?
+            as u32)
+    };
+
+    let secret = auth::generate_api_key();
+    let hash = auth::hash_api_key(&secret);
+    let id = db.store_api_key(name, &hash, &scopes, quota).map_err(db_error)?;
+    log(format!("Created api key {} ({})", id, name));
+
+    // the only time the key itself is shown
+    let mut key = key_json(&db.api_key_by_hash(&hash).map_err(db_error)?);
+    key["key"] = json!(secret);
+    let mut r = json_response(ResponseCode::Created, key);
+    r.set_header("Location", &format!("{}/api/v1/keys/{}", config().base_url, id));
+    Ok(r)
+}
+
+fn list_keys(db: &dyn Database) -> Result<Response, HandlerError> {
+    let keys = db.list_api_keys().map_err(db_error)?;
+    Ok(json_response(ResponseCode::Ok, Value::Array(keys.iter().map(key_json).collect())))
+}
+
+fn revoke_key(id: &str, db: &dyn Database) -> Result<Response, HandlerError> {
+    let id: i64 = id.parse()
+        .map_err(|_| api_error(ResponseCode::NotFound, "not_found", "Api key does not exist"))?;
+    log(format!("Revoking api key {}", id));
+    db.revoke_api_key(id).map_err(|e| match e {
+        DbError::NotFound => api_error(ResponseCode::NotFound, "not_found", "Api key does not exist"),
+        e => db_error(e)
+    })?;
+    Ok(Response {
+        code: ResponseCode::NoContent,
+        custom_headers: None,
+        body: ResponseBody::Empty
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    /// sends a json request through the router, answers with the status and the json body (`null` if there is none)
+    fn call(db: &mut dyn Database, method: &str, path: &str, token: Option<&str>, body: Option<Value>) -> (u16, Value) {
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let auth = token.map(|t| format!("Authorization: Bearer {}\r\n", t)).unwrap_or_default();
+        let raw = format!("{} {} HTTP/1.1\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                          method, path, auth, body.len(), body);
+        let res = crate::handle_request(&testutil::request(&raw), db);
+        (res.code as u16, serde_json::from_slice(res.body.get_bytes()).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn manages_links() {
+        let mut db = testutil::memory_db();
+        let db = db.as_mut();
+        let (code, link) = call(db, "POST", "/api/v1/links", Some("pw"), Some(json!({"long": "https://example.org", "short": "mylink"})));
+        assert_eq!(code, 201, "{}", link);
+        assert_eq!(link["short"], "mylink");
+        assert_eq!(link["url"], "http://localhost/mylink");
+
+        let (code, link) = call(db, "PATCH", "/api/v1/links/mylink", Some("admin:secret"), Some(json!({"long": "example.com"})));
+        assert_eq!(code, 200, "{}", link);
+        assert_eq!(link["long"], "https://example.com");
+        let (_, history) = call(db, "GET", "/api/v1/links/mylink/history", Some("pw"), None);
+        assert_eq!(history[0]["old_long"], "https://example.org");
+        assert_eq!(history[0]["actor"], "password");
+
+        let (code, _) = call(db, "POST", "/api/v1/links", Some("pw"), Some(json!({"long": "https://example.org", "short": "mylink"})));
+        assert_eq!(code, 409);
+        assert_eq!(call(db, "DELETE", "/api/v1/links/mylink", Some("pw"), None).0, 204);
+        let (code, err) = call(db, "GET", "/api/v1/links/mylink", Some("pw"), None);
+        assert_eq!((code, &err["error"]["code"]), (404, &json!("not_found")));
+    }
+
+    #[test]
+    fn rejects_integer_fields_of_other_types() {
+        let mut db = testutil::memory_db();
+        let db = db.as_mut();
+        for (field, value, code) in [("max_redirects", json!("2"), "invalid_max_redirects"),
+                                     ("max_redirects", json!(1.5), "invalid_max_redirects"),
+                                     ("max_redirects", json!(-1), "invalid_max_redirects"),
+                                     ("max_redirects", json!(0), "invalid_max_redirects"),
+                                     ("max_redirects", json!(2_147_483_648u32), "invalid_max_redirects"),
+                                     ("redirect_code", json!("302"), "invalid_redirect_code"),
+                                     ("redirect_code", json!(302.0), "invalid_redirect_code"),
+                                     ("redirect_code", json!(65838), "invalid_redirect_code")] {
+            let mut body = json!({"long": "https://example.org"});
+            body[field] = value.clone();
+            let (status, err) = call(db, "POST", "/api/v1/links", Some("pw"), Some(body));
+            assert_eq!((status, &err["error"]["code"]), (400, &json!(code)), "{} {}", field, value);
+        }
+        let (status, link) = call(db, "POST", "/api/v1/links", Some("pw"),
+                                  Some(json!({"long": "https://example.org", "max_redirects": 2, "redirect_code": 303})));
+        assert_eq!(status, 201, "{}", link);
+        assert_eq!((&link["max_redirects"], &link["redirect_code"]), (&json!(2), &json!(303)));
+    }
+
+    #[test]
+    fn rolls_back_after_other_links_were_deleted() {
+        let mut db = testutil::memory_db();
+        let db = db.as_mut();
+        for short in ["first", "second"] {
+            call(db, "POST", "/api/v1/links", Some("pw"), Some(json!({"long": "https://a.org", "short": short})));
+            call(db, "PATCH", &format!("/api/v1/links/{}", short), Some("pw"), Some(json!({"long": "https://b.org"})));
+        }
+        let (_, history) = call(db, "GET", "/api/v1/links/second/history", Some("pw"), None);
+        let id = history[0]["id"].clone();
+        assert_eq!(call(db, "DELETE", "/api/v1/links/first", Some("pw"), None).0, 204);
+        call(db, "PATCH", "/api/v1/links/second", Some("pw"), Some(json!({"long": "https://c.org"})));
+
+        let (code, link) = call(db, "POST", "/api/v1/links/second/rollback", Some("pw"), Some(json!({"id": id})));
+        assert_eq!(code, 200, "{}", link);
+        assert_eq!(link["long"], "https://a.org");
+        let (_, history) = call(db, "GET", "/api/v1/links/second/history", Some("pw"), None);
+        let ids: Vec<_> = history.as_array().unwrap().iter().map(|c| c["id"].as_i64().unwrap()).collect();
+        assert!(ids.windows(2).all(|w| w[0] > w[1]), "{:?}", ids);
+    }
+
+    #[test]
+    fn checks_tokens_and_scopes() {
+        let mut db = testutil::memory_db();
+        let db = db.as_mut();
+        assert_eq!(call(db, "GET", "/api/v1/keys", None, None).0, 401);
+        assert_eq!(call(db, "GET", "/api/v1/keys", Some("wrong"), None).0, 401);
+        assert_eq!(call(db, "GET", "/api/v1/keys", Some("admin:pw"), None).0, 401);
+
+        let (code, key) = call(db, "POST", "/api/v1/keys", Some("admin:secret"), Some(json!({"name": "ci", "scopes": ["create"]})));
+        assert_eq!(code, 201, "{}", key);
+        let secret = key["key"].as_str().unwrap().to_owned();
+        let (code, link) = call(db, "POST", "/api/v1/links", Some(&secret), Some(json!({"long": "https://example.org"})));
+        assert_eq!(code, 201, "{}", link);
+        let (code, err) = call(db, "GET", &format!("/api/v1/links/{}", link["short"].as_str().unwrap()), Some(&secret), None);
+        assert_eq!((code, &err["error"]["code"]), (403, &json!("forbidden")));
+        assert_eq!(call(db, "GET", "/api/v1/keys", Some(&secret), None).0, 403);
+
+        assert_eq!(call(db, "DELETE", &format!("/api/v1/keys/{}", key["id"]), Some("pw"), None).0, 204);
+        assert_eq!(call(db, "DELETE", "/api/v1/keys/-1", Some("pw"), None).0, 404);
+        assert_eq!(call(db, "POST", "/api/v1/links", Some(&secret), Some(json!({"long": "https://example.org"}))).0, 401);
+    }
+}
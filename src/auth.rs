@@ -0,0 +1,251 @@
+use crate::request::{Request, RequestBody};
+use crate::database::{Database, DbError, ApiKey};
+use crate::handler::rate_limit_client;
+use crate::ratelimit::{self, Action};
+use crate::log;
+use rand::Rng;
+use sha2::{Sha256, Digest};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use subtle::ConstantTimeEq;
+
+/// issued api keys start with this, everything else is checked against the `passwords` table
+/// (`<name>:<password>` for named passwords)
+pub const API_KEY_PREFIX: &str = "shorty_";
+
+/// What an api key is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// create and update short urls
+    Create,
+    Delete,
+    /// read the logged clicks and the change history of short urls through the api,
+    /// the counts on `/status` are public
+    ReadStats,
+    /// everything, including settings and api keys
+    Admin
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Create => "create",
+            Scope::Delete => "delete",
+            Scope::ReadStats => "read-stats",
+            Scope::Admin => "admin"
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Scope> {
+        match s.trim() {
+            "create" => Some(Scope::Create),
+            "delete" => Some(Scope::Delete),
+            "read-stats" => Some(Scope::ReadStats),
+            "admin" => Some(Scope::Admin),
+            _ => None
+        }
+    }
+
+    /// the comma separated list as stored in `api_keys.scopes`, unknown scopes are skipped
+    pub fn parse_list(s: &str) -> Vec<Scope> {
+        s.split(',').filter_map(Scope::parse).collect()
+    }
+
+    pub fn join(scopes: &[Scope]) -> String {
+        scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(",")
+    }
+}
+
+/// Who sent a request
+pub enum Principal {
+    /// one of the legacy `passwords`, allowed to do everything
+    Password,
+    Key(ApiKey)
+}
+
+impl Principal {
+    pub fn allows(&self, scope: Scope) -> bool {
+        match self {
+            Principal::Password => true,
+            Principal::Key(key) => key.scopes.contains(&Scope::Admin) || key.scopes.contains(&scope)
+        }
+    }
+
+    /// who did something, as stored in `url_history.actor`
+    pub fn actor(&self) -> String {
+        match self {
+            Principal::Password => "password".into(),
+            Principal::Key(key) => format!("key {} ({})", key.id, key.name)
+        }
+    }
+
+    pub fn api_key(&self) -> Option<&ApiKey> {
+        match self {
+            Principal::Password => None,
+            Principal::Key(key) => Some(key)
+        }
+    }
+}
+
+/// the value stored in `api_keys.key_hash`, keys are random so a plain sha256 is enough
+pub fn hash_api_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// a new random api key, only its hash gets stored
+pub fn generate_api_key() -> String {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    let mut rg = rand::thread_rng();
+    let random: String = (0..32).map(|_| CHARS[rg.gen_range(0, CHARS.len())] as char).collect();
+    format!("{}{}", API_KEY_PREFIX, random)
+}
+
+/// the token of an `Authorization: Bearer <token>` header
+pub fn bearer_token(req: &Request) -> Option<&str> {
+    req.header("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| t.trim())
+}
+
+/// salted argon2 hash (phc string) as stored in `passwords` and `urls.passphrase_hash`
+pub fn hash_password(pw: &str) -> String {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let salt = SaltString::encode_b64(&salt).expect("16 bytes are a valid salt");
+    Argon2::default().hash_password(pw.as_bytes(), &salt)
+        .expect("argon2 with default params can hash every password")
+        .to_string()
+}
+
+/// checks `pw` against a hash of `hash_password`
+pub fn verify_password_hash(pw: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(pw.as_bytes(), &hash).is_ok())
+}
+
+/// rows from before hashing hold the password itself
+fn is_password_hash(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+/// hashes are verified and plaintext rows compared in constant time
+fn password_matches(pw: &str, stored: &str) -> bool {
+    if is_password_hash(stored) {
+        verify_password_hash(pw, stored)
+    } else {
+        bool::from(stored.as_bytes().ct_eq(pw.as_bytes()))
+    }
+}
+
+/// The stored row matching `credential`. `<name>:<password>` is only checked against the password of that name,
+/// everything else against each unnamed one
+pub fn find_password(db: &dyn Database, credential: &str) -> Result<Option<String>, DbError> {
+    if let Some((name, pw)) = credential.split_once(':') {
+        match db.password_by_name(name) {
+            Ok(stored) => return Ok(Some(stored).filter(|stored| password_matches(pw, stored))),
+            // unnamed passwords may contain colons too
+            Err(DbError::NotFound) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(db.unnamed_passwords()?.into_iter().find(|stored| password_matches(credential, stored)))
+}
+
+/// Checks a password, plaintext rows are replaced by their hash on the first successful login
+pub fn check_password(db: &dyn Database, credential: &str) -> Result<bool, DbError> {
+    match find_password(db, credential)? {
+        Some(stored) => {
+            if !is_password_hash(&stored) {
+                log("Replacing plaintext password by its hash");
+                match db.replace_password(&stored, &hash_password(credential)) {
+                    // another worker was faster
+                    Ok(()) | Err(DbError::NotFound) => {},
+                    Err(e) => return Err(e)
+                }
+            }
+            Ok(true)
+        },
+        None => Ok(false)
+    }
+}
+
+/// Why a token could not be checked
+#[derive(Debug)]
+pub enum AuthError {
+    /// too many failed logins from this client, with the seconds until the next try
+    TooManyFailures(u64),
+    Db(DbError)
+}
+
+impl From<DbError> for AuthError {
+    fn from(e: DbError) -> Self {
+        AuthError::Db(e)
+    }
+}
+
+/// Checks an api key or password, `None` if it is unknown or revoked. Updates `last_used` of valid keys.
+/// Clients that failed too often (`SHORTY_RATE_LIMIT_AUTH`) are refused before anything is checked
+pub fn authenticate(req: &Request, db: &dyn Database, token: &str) -> Result<Option<Principal>, AuthError> {
+    let client = rate_limit_client(req, None);
+    ratelimit::check(Action::FailedAuth, &client).map_err(AuthError::TooManyFailures)?;
+    let principal = check_token(db, token)?;
+    if principal.is_some() {
+        // only failures count
+        ratelimit::refund(Action::FailedAuth, &client);
+    }
+    Ok(principal)
+}
+
+fn check_token(db: &dyn Database, token: &str) -> Result<Option<Principal>, DbError> {
+    if token.starts_with(API_KEY_PREFIX) {
+        match db.api_key_by_hash(&hash_api_key(token)) {
+            Ok(key) if !key.revoked => {
+                db.touch_api_key(key.id)?;
+                Ok(Some(Principal::Key(key)))
+            },
+            Ok(_) | Err(DbError::NotFound) => Ok(None),
+            Err(e) => Err(e)
+        }
+    } else if check_password(db, token)? {
+        Ok(Some(Principal::Password))
+    } else {
+        Ok(None)
+    }
+}
+
+/// For the html forms: the bearer token, or else the `password` form field
+pub fn authenticate_form(req: &Request, db: &dyn Database) -> Result<Option<Principal>, AuthError> {
+    let token = bearer_token(req).or_else(|| match &req.body {
+        Some(RequestBody::FormUrlEncoded(map)) => map.get("password").map(|pw| pw.as_str()),
+        _ => None
+    });
+    match token {
+        Some(token) => authenticate(req, db, token),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn finds_passwords_by_name() {
+        let db = testutil::memory_db();
+        assert!(find_password(db.as_ref(), "admin:secret").unwrap().is_some());
+        assert!(find_password(db.as_ref(), "admin:pw").unwrap().is_none());
+        // named passwords are never tried without their name
+        assert!(find_password(db.as_ref(), "secret").unwrap().is_none());
+        assert!(find_password(db.as_ref(), "pw").unwrap().is_some());
+        assert!(find_password(db.as_ref(), "nobody:pw").unwrap().is_none());
+    }
+
+    #[test]
+    fn checks_unnamed_passwords_with_colons() {
+        let db = testutil::memory_db();
+        db.add_password(None, "other:plain").unwrap();
+        assert!(check_password(db.as_ref(), "other:plain").unwrap());
+        // hashed on the first login
+        assert!(db.unnamed_passwords().unwrap().iter().all(|p| is_password_hash(p)));
+        assert!(check_password(db.as_ref(), "other:plain").unwrap());
+    }
+}
@@ -0,0 +1,31 @@
+/// Parts of the `User-Agent` of link unfurlers (chat apps fetching a preview) and search crawlers,
+/// matched case-insensitively. Extended at runtime by the `bot-patterns` setting
+pub const BOT_PATTERNS: &[&str] = &[
+    // Googlebot, bingbot, Slackbot, Twitterbot, Discordbot, TelegramBot, LinkedInBot, Applebot, ...
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "facebookcatalog",
+    // Slack-ImgProxy
+    "slack",
+    // Microsoft Teams / Skype (SkypeUriPreview, MicrosoftPreview), BingPreview
+    "preview",
+    "whatsapp",
+    "embedly",
+    "vkshare",
+    "pinterest",
+    "headlesschrome",
+    "lighthouse"
+];
+
+/// Whether the request comes from a bot instead of a human, `extra` are additional lowercase patterns.
+/// Requests without `User-Agent` are counted as humans
+pub fn is_bot(user_agent: Option<&str>, extra: &[String]) -> bool {
+    let user_agent = match user_agent {
+        Some(ua) => ua.to_ascii_lowercase(),
+        None => return false
+    };
+    BOT_PATTERNS.iter().any(|p| user_agent.contains(p)) || extra.iter().any(|p| user_agent.contains(p.as_str()))
+}
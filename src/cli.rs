@@ -0,0 +1,60 @@
+use crate::auth;
+use crate::database::{Backend, Database, DbError};
+use std::io::BufRead;
+
+const USAGE: &str = "Usage:
+  shorty-rs                    start the server
+  shorty-rs password add <name>    read a new password from stdin and store its hash, log in with <name>:<password>
+  shorty-rs password rotate        read the old and the new password from stdin (one per line) and replace it";
+
+/// Admin commands, run instead of the server if there are arguments.
+/// They work on the database of `SHORTY_DB_PATH`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    match args.as_slice() {
+        ["password", "add", name] => {
+            if name.is_empty() || name.contains(':') {
+                return Err("The name must not be empty or contain a colon".into());
+            }
+            let db = connect()?;
+            let pw = read_password("New password: ")?;
+            db.add_password(Some(name), &auth::hash_password(&pw)).map_err(|e| match e {
+                DbError::Conflict => format!("A password called {} exists already", name),
+                e => e.to_string()
+            })?;
+            eprintln!("Password added");
+            Ok(())
+        },
+        ["password", "rotate"] => {
+            let db = connect()?;
+            let old = read_password("Old password: ")?;
+            let new = read_password("New password: ")?;
+            let stored = auth::find_password(db.as_ref(), &old).map_err(|e| e.to_string())?
+                .ok_or("Old password is wrong")?;
+            db.replace_password(&stored, &auth::hash_password(&new)).map_err(|e| e.to_string())?;
+            eprintln!("Password replaced");
+            Ok(())
+        },
+        _ => Err(USAGE.into())
+    }
+}
+
+fn connect() -> Result<Box<dyn Database>, String> {
+    let path = std::env::var("SHORTY_DB_PATH").map_err(|_| "Set SHORTY_DB_PATH")?;
+    if path == ":memory:" {
+        return Err("The in-memory database takes its passwords from SHORTY_MEMORY_PASSWORDS".into());
+    }
+    Backend::from_path(&path).connect().map_err(|e| e.to_string())
+}
+
+/// one line of stdin, so passwords don't end up in the shell history
+fn read_password(prompt: &str) -> Result<String, String> {
+    eprint!("{}", prompt);
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())?;
+    let pw = line.trim_end_matches(&['\r', '\n'][..]);
+    if pw.is_empty() {
+        return Err("Empty password".into());
+    }
+    Ok(pw.to_owned())
+}
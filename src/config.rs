@@ -0,0 +1,367 @@
+use crate::database::{Database, DbError};
+use crate::response::{json_string, ResponseCode};
+use crate::log;
+use crate::ratelimit::Limit;
+use crate::proxy::{Cidr, ProxyHeader};
+use rand::Rng;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The static configuration, read once from the env-vars at startup
+#[derive(Debug)]
+pub struct Config {
+    pub base_url: String,
+    pub database_path: String,
+    pub port: u16,
+    pub debug: bool,
+    /// number of worker threads handling connections
+    pub workers: usize,
+    /// seconds an idle keep-alive connection is held open
+    pub keep_alive_timeout: u64,
+    /// workers that may wait on idle keep-alive connections, always less than `workers`
+    pub max_idle_connections: usize,
+    /// seconds a client has to send the complete request
+    pub read_timeout: u64,
+    /// seconds a client has to receive the response
+    pub write_timeout: u64,
+    /// max bytes of request line and headers
+    pub max_header_size: usize,
+    /// max number of header lines
+    pub max_headers: usize,
+    /// max bytes of the request body
+    pub max_body_size: usize,
+    /// seconds between two runs of the sweeper, which archives expired urls
+    pub sweep_interval: u64,
+    /// share of redirects logged in `clicks`, 0 to 1
+    pub click_sample_rate: f64,
+    /// days until logged clicks are deleted by the sweeper
+    pub click_retention_days: u64,
+    /// key of the hmac in `ip_hash`, random if not configured
+    pub ip_hash_secret: String,
+    /// the key before the last rotation, its hashes still count against the quota
+    pub ip_hash_previous_secret: Option<String>,
+    /// requests per client, `None` for no limit
+    pub rate_limit_create: Option<Limit>,
+    pub rate_limit_free: Option<Limit>,
+    pub rate_limit_redirect: Option<Limit>,
+    /// failed logins with passwords or api keys, successful ones are free
+    pub rate_limit_auth: Option<Limit>,
+    /// proxies whose `Forwarded`/`X-Forwarded-For`/`X-Real-IP` headers are believed, empty to ignore them
+    pub trusted_proxies: Vec<Cidr>,
+    /// the only header of the trusted proxies that is read
+    pub proxy_header: ProxyHeader,
+    /// pem files of the certificate chain and its private key, https is served if both are set
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_port: u16,
+    /// answer plain http requests with a redirect to https
+    pub https_redirect: bool
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+impl Config {
+    /// Reads the config from the env-vars, panics if a required one is missing
+    pub fn from_env() -> Self {
+        let tls_cert = std::env::var("SHORTY_TLS_CERT").ok().filter(|s| !s.is_empty());
+        let tls_key = std::env::var("SHORTY_TLS_KEY").ok().filter(|s| !s.is_empty());
+        if tls_cert.is_some() != tls_key.is_some() {
+            panic!("Set both SHORTY_TLS_CERT and SHORTY_TLS_KEY for https");
+        }
+        let https_redirect = std::env::var("SHORTY_HTTPS_REDIRECT").is_ok();
+        if https_redirect && tls_cert.is_none() {
+            log("SHORTY_HTTPS_REDIRECT is set without SHORTY_TLS_CERT, not redirecting");
+        }
+        let https_redirect = https_redirect && tls_cert.is_some();
+
+        let workers = env_positive("SHORTY_WORKERS", std::thread::available_parallelism().map_or(4, |n| n.get()));
+
+        Config {
+            base_url: std::env::var("SHORTY_BASE_URL").expect("Set SHORTY_BASE_URL"),
+            database_path: std::env::var("SHORTY_DB_PATH").expect("Set SHORTY_DB_PATH"),
+            port: std::env::var("SHORTY_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(80),
+            debug: std::env::var("SHORTY_DEBUG").is_ok(),
+            workers,
+            keep_alive_timeout: env_positive("SHORTY_KEEP_ALIVE_TIMEOUT", 5),
+            max_idle_connections: std::env::var("SHORTY_MAX_IDLE_CONNECTIONS").ok().and_then(|s| s.parse().ok())
+                .unwrap_or(workers / 2)
+                .min(workers - 1),
+            read_timeout: env_positive("SHORTY_READ_TIMEOUT", 10),
+            write_timeout: env_positive("SHORTY_WRITE_TIMEOUT", 10),
+            max_header_size: env_positive("SHORTY_MAX_HEADER_SIZE", 8 * 1024),
+            max_headers: env_positive("SHORTY_MAX_HEADERS", 64),
+            max_body_size: env_positive("SHORTY_MAX_BODY_SIZE", 64 * 1024),
+            sweep_interval: env_positive("SHORTY_SWEEP_INTERVAL", 60 * 60),
+            click_sample_rate: std::env::var("SHORTY_CLICK_SAMPLE_RATE").ok().and_then(|s| s.parse().ok())
+                .filter(|r| (0.0..=1.0).contains(r))
+                .unwrap_or(1.0),
+            click_retention_days: env_positive("SHORTY_CLICK_RETENTION_DAYS", 90),
+            ip_hash_secret: std::env::var("SHORTY_IP_HASH_SECRET").ok().filter(|s| !s.is_empty())
+                .unwrap_or_else(|| {
+                    log("SHORTY_IP_HASH_SECRET is not set, using a random one. Quotas restart with the server");
+                    let secret: [u8; 32] = rand::thread_rng().gen();
+                    secret.iter().map(|b| format!("{:02x}", b)).collect()
+                }),
+            ip_hash_previous_secret: std::env::var("SHORTY_IP_HASH_PREVIOUS_SECRET").ok().filter(|s| !s.is_empty()),
+            rate_limit_create: env_rate_limit("SHORTY_RATE_LIMIT_CREATE", Limit { per_minute: 10.0, burst: 10.0 }),
+            rate_limit_free: env_rate_limit("SHORTY_RATE_LIMIT_FREE", Limit { per_minute: 60.0, burst: 60.0 }),
+            rate_limit_redirect: env_rate_limit("SHORTY_RATE_LIMIT_REDIRECT", Limit { per_minute: 600.0, burst: 600.0 }),
+            rate_limit_auth: env_rate_limit("SHORTY_RATE_LIMIT_AUTH", Limit { per_minute: 5.0, burst: 10.0 }),
+            trusted_proxies: env_cidrs("SHORTY_TRUSTED_PROXIES"),
+            proxy_header: match std::env::var("SHORTY_PROXY_HEADER") {
+                Ok(s) => ProxyHeader::parse(&s).unwrap_or_else(|e| {
+                    log(format!("SHORTY_PROXY_HEADER: {}, using X-Forwarded-For", e));
+                    ProxyHeader::XForwardedFor
+                }),
+                Err(_) => ProxyHeader::XForwardedFor
+            },
+            tls_cert,
+            tls_key,
+            tls_port: std::env::var("SHORTY_TLS_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(443),
+            https_redirect
+        }
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some()
+    }
+}
+
+impl Config {
+    /// `database_path` with the password of a connection url replaced, for logs and `/config`
+    pub fn database_display(&self) -> String {
+        let path = &self.database_path;
+        if let Some(scheme_end) = path.find("://") {
+            let rest = &path[scheme_end + 3..];
+            let authority_end = rest.find('/').unwrap_or(rest.len());
+            if let Some(at) = rest[..authority_end].rfind('@') {
+                if let Some(colon) = rest[..at].find(':') {
+                    return format!("{}{}:***{}", &path[..scheme_end + 3], &rest[..colon], &rest[at..]);
+                }
+            }
+        }
+        path.clone()
+    }
+}
+
+/// parses the env-var as number greater than zero, or returns `default`
+fn env_positive<T: std::str::FromStr + PartialOrd + Default>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|s| s.parse().ok())
+        .filter(|v| *v > T::default())
+        .unwrap_or(default)
+}
+
+/// parses the env-var with `Limit::parse`, or returns `default` if it is missing or invalid
+fn env_rate_limit(name: &str, default: Limit) -> Option<Limit> {
+    match std::env::var(name) {
+        Ok(s) => Limit::parse(&s).unwrap_or_else(|e| {
+            log(format!("{}: {}, using the default", name, e));
+            Some(default)
+        }),
+        Err(_) => Some(default)
+    }
+}
+
+/// parses the comma separated ip ranges of the env-var, skipping invalid ones
+fn env_cidrs(name: &str) -> Vec<Cidr> {
+    std::env::var(name).unwrap_or_default().split(',')
+        .filter(|s| !s.trim().is_empty())
+        .filter_map(|s| Cidr::parse(s).map_err(|e| log(format!("{}: {}, ignoring it", name, e))).ok())
+        .collect()
+}
+
+/// Stores the config globally, must be called once before `config()`
+pub(crate) fn init_config(config: Config) {
+    CONFIG.set(config).expect("Config initialized twice");
+}
+
+/// The global config
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("Config not initialized")
+}
+
+
+/// Settings which can be changed at runtime via `/config`, stored in the `settings` table
+#[derive(Debug)]
+pub struct Settings {
+    /// how many urls one ip can create in 7 days
+    pub max_urls_per_week: u32,
+    /// length of generated random short urls
+    pub short_url_length: usize,
+    /// forbidden short urls in addition to `handler::RESERVED_URLS`
+    pub reserved_words: Vec<String>,
+    /// redirect status of urls without their own, one of 301, 302, 303, 307 and 308
+    pub default_redirect_code: u16,
+    /// lowercase user agent parts counted as bots in addition to `bots::BOT_PATTERNS`
+    pub bot_patterns: Vec<String>
+}
+
+pub const SETTING_MAX_URLS_PER_WEEK: &str = "max-urls-per-week";
+pub const SETTING_SHORT_URL_LENGTH: &str = "short-url-length";
+pub const SETTING_RESERVED_WORDS: &str = "reserved-words";
+pub const SETTING_DEFAULT_REDIRECT_CODE: &str = "default-redirect-code";
+pub const SETTING_BOT_PATTERNS: &str = "bot-patterns";
+
+/// all keys that can be changed
+pub const SETTING_KEYS: [&str; 5] = [
+    SETTING_MAX_URLS_PER_WEEK,
+    SETTING_SHORT_URL_LENGTH,
+    SETTING_RESERVED_WORDS,
+    SETTING_DEFAULT_REDIRECT_CODE,
+    SETTING_BOT_PATTERNS
+];
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_urls_per_week: 100,
+            short_url_length: 5,
+            reserved_words: vec![],
+            default_redirect_code: 301,
+            bot_patterns: vec![]
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the settings from the database, missing values are set to their default
+    pub fn load(db: &dyn Database) -> Result<Self, DbError> {
+        let mut settings = Settings::default();
+        for key in SETTING_KEYS.iter() {
+            if let Some(value) = db.get_setting(key)? {
+                // values are validated before storing
+                let _ = settings.apply(key, &value);
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The settings of `Settings::load`, kept in memory for `SETTINGS_TTL` since they are needed by every redirect
+    pub fn cached(db: &dyn Database) -> Result<Arc<Self>, DbError> {
+        let now = Instant::now();
+        let generation = {
+            let cache = settings_cache();
+            if let Some(settings) = cache.get(now) {
+                return Ok(settings);
+            }
+            cache.generation
+        };
+        // loaded without the lock, so other workers keep redirecting
+        let settings = Arc::new(Settings::load(db)?);
+        settings_cache().store(generation, now, settings.clone());
+        Ok(settings)
+    }
+
+    /// Drops the cached settings after they were changed, see `Settings::cached`
+    pub fn invalidate() {
+        settings_cache().invalidate();
+    }
+
+    /// Validates and sets one setting (without storing it, see `Database::set_setting`)
+    pub fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            SETTING_MAX_URLS_PER_WEEK => {
+                self.max_urls_per_week = value.trim().parse().ok()
+                    .filter(|m| *m > 0)
+                    .ok_or_else(|| format!("{} must be a positive number", key))?;
+            },
+            SETTING_SHORT_URL_LENGTH => {
+                let len: usize = value.trim().parse()
+                    .map_err(|_| format!("{} must be a positive number", key))?;
+                if !(3..=64).contains(&len) {
+                    return Err(format!("{} must be between 3 and 64", key));
+                }
+                self.short_url_length = len;
+            },
+            SETTING_RESERVED_WORDS => {
+                self.reserved_words = value.split(',')
+                    .map(|w| w.trim())
+                    .filter(|w| !w.is_empty())
+                    .map(|w| w.into())
+                    .collect();
+            },
+            SETTING_DEFAULT_REDIRECT_CODE => {
+                self.default_redirect_code = value.trim().parse().ok()
+                    .filter(|c| ResponseCode::redirect(*c).is_some())
+                    .ok_or_else(|| format!("{} must be 301, 302, 303, 307 or 308", key))?;
+            },
+            SETTING_BOT_PATTERNS => {
+                self.bot_patterns = value.split(',')
+                    .map(|p| p.trim().to_ascii_lowercase())
+                    .filter(|p| !p.is_empty())
+                    .collect();
+            },
+            _ => return Err(format!("Unknown setting {}", key))
+        }
+        Ok(())
+    }
+
+    /// all settings as json object
+    pub fn to_json(&self) -> String {
+        format!("{{{}:{},{}:{},{}:[{}],{}:{},{}:[{}]}}",
+                json_string(SETTING_MAX_URLS_PER_WEEK), self.max_urls_per_week,
+                json_string(SETTING_SHORT_URL_LENGTH), self.short_url_length,
+                json_string(SETTING_RESERVED_WORDS),
+                self.reserved_words.iter().map(|w| json_string(w)).collect::<Vec<_>>().join(","),
+                json_string(SETTING_DEFAULT_REDIRECT_CODE), self.default_redirect_code,
+                json_string(SETTING_BOT_PATTERNS),
+                self.bot_patterns.iter().map(|p| json_string(p)).collect::<Vec<_>>().join(","))
+    }
+}
+
+/// how long `Settings::cached` keeps them, other instances sharing the database see changes after this
+const SETTINGS_TTL: Duration = Duration::from_secs(60);
+
+struct SettingsCache {
+    settings: Option<(Instant, Arc<Settings>)>,
+    /// incremented by every `invalidate`, settings loaded before are not stored
+    generation: u64
+}
+
+impl SettingsCache {
+    fn get(&self, now: Instant) -> Option<Arc<Settings>> {
+        self.settings.as_ref()
+            .filter(|(loaded, _)| now.duration_since(*loaded) < SETTINGS_TTL)
+            .map(|(_, settings)| settings.clone())
+    }
+
+    fn store(&mut self, generation: u64, now: Instant, settings: Arc<Settings>) {
+        if generation == self.generation {
+            self.settings = Some((now, settings));
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.settings = None;
+        self.generation += 1;
+    }
+}
+
+/// Settings shared by all workers of this process
+static SETTINGS: Mutex<SettingsCache> = Mutex::new(SettingsCache { settings: None, generation: 0 });
+
+fn settings_cache() -> MutexGuard<'static, SettingsCache> {
+    SETTINGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_settings_until_invalidated() {
+        let mut cache = SettingsCache { settings: None, generation: 0 };
+        let now = Instant::now();
+        cache.store(0, now, Arc::new(Settings { short_url_length: 8, ..Settings::default() }));
+        assert_eq!(cache.get(now + Duration::from_secs(1)).map(|s| s.short_url_length), Some(8));
+        assert!(cache.get(now + SETTINGS_TTL).is_none());
+
+        cache.invalidate();
+        assert!(cache.get(now).is_none());
+        // loaded before the change
+        cache.store(0, now, Arc::new(Settings::default()));
+        assert!(cache.get(now).is_none());
+        cache.store(1, now, Arc::new(Settings::default()));
+        assert_eq!(cache.get(now).map(|s| s.short_url_length), Some(5));
+    }
+}
@@ -1,71 +1,263 @@
-use rusqlite::{Connection, params};
-use std::path::Path;
+use std::fmt;
+use crate::auth::Scope;
 
+mod sqlite;
+pub use sqlite::SQLiteDB;
 
-pub trait Database {
-    fn store_shortened(&self, long_url: &str, short_url: &str, hashed_ip: u32) -> Result<(), String>;
-    /// get the long url and increment counter + update last visited
-    fn forward(&mut self, short_url: &str) -> Result<String, ()>;
+mod memory;
+pub use memory::MemoryDB;
 
-    /// same as forward, but no increment / update
-    fn peek_long_url(&self, short_url: &str) -> Result<String, ()>;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresDB;
 
-    fn is_password(&self, pw: &str) -> bool;
 
-    fn urls_stored_last_7_days(&self, hashed_ip: u32) -> u32;
+/// The error of every `Database` method
+#[derive(Debug)]
+pub enum DbError {
+    /// no matching short url / row
+    NotFound,
+    /// the short url exists, but its `expires_at` has passed or its `max_redirects` are used up
+    Expired,
+    /// a unique value (e.g. the short url) is already stored
+    Conflict,
+    /// locked by another connection for too long
+    Busy,
+    /// the database file is damaged or not a database
+    Corrupt,
+    Other(String)
 }
 
-pub struct SQLiteDB {
-    connection: Connection
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::NotFound => write!(f, "not found"),
+            DbError::Expired => write!(f, "expired"),
+            DbError::Conflict => write!(f, "already exists"),
+            DbError::Busy => write!(f, "database is busy"),
+            DbError::Corrupt => write!(f, "database is corrupt"),
+            DbError::Other(e) => write!(f, "database error: {}", e)
+        }
+    }
 }
 
-impl SQLiteDB {
-    pub fn init_database<P: AsRef<Path>>(path: P) -> Result<Box<dyn Database>, String> {
-        let connection = Connection::open(path).map_err(|e| e.to_string())?;
-        Ok(Box::new(SQLiteDB{connection}))
-    }
+/// usage statistics of one short url
+pub struct UrlStats {
+    pub long: String,
+    pub created: String,
+    /// visits of humans
+    pub redirects: u32,
+    /// visits of link unfurlers and crawlers, see `bots::is_bot`
+    pub bot_redirects: u32,
+    /// estimated number of distinct human visitors, a visitor coming back on another day counts again
+    pub unique_visitors: u32,
+    pub last_redirect: String,
+    pub expires_at: Option<String>,
+    pub max_redirects: Option<u32>,
+    /// visitors need a passphrase
+    pub protected: bool,
+    pub redirect_code: Option<u16>
 }
 
+/// where `forward` sends the visitor
+pub struct Redirect {
+    pub long: String,
+    /// 301, 302, 303, 307 or 308, `None` uses the `default-redirect-code` setting
+    pub redirect_code: Option<u16>
+}
 
-impl Database for SQLiteDB {
-    fn store_shortened(&self, long_url: &str, short_url: &str, hashed_ip: u32) -> Result<(), String> {
-        self.connection.execute(
-            "INSERT INTO urls (short, long, ip_hash, created, redirects, last_redirect)\
-            VALUES (?, ?, ?, datetime('now', 'localtime'), 0, datetime('now', 'localtime'))",
-            params![short_url, long_url, hashed_ip]
-        ).map(|_| ()).map_err(|e| e.to_string())
-    }
+/// what `Database::forward` counts
+pub struct Visit<'a> {
+    /// link unfurlers and crawlers only increment `bot_redirects`, which still uses up `max_redirects`
+    pub bot: bool,
+    /// logged in `clicks`, `None` if it wasn't sampled
+    pub click: Option<Click<'a>>,
+    /// hash of the visitor with the salt of the day, inserted into the sketches of unique visitors (see `hll`)
+    pub visitor: Option<u64>
+}
 
-    fn urls_stored_last_7_days(&self, hashed_ip: u32) -> u32 {
-        self.connection.query_row(
-            "SELECT Count(*) FROM urls WHERE ip_hash = ? AND created > datetime('now', 'localtime', '-1 year')", &[hashed_ip], |row| row.get(0))
-            .unwrap_or(0)
-    }
+/// one visit logged in `clicks`
+pub struct Click<'a> {
+    pub ip_hash: String,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub accept_language: Option<&'a str>
+}
+
+/// clicks of one short url, aggregated by `Database::click_stats`
+pub struct ClickStats {
+    /// day (`%Y-%m-%d`) and clicks, oldest first
+    pub daily: Vec<(String, u32)>,
+    /// hour (`%Y-%m-%d %H:00`) and clicks, oldest first
+    pub hourly: Vec<(String, u32)>,
+    /// the 10 referers with the most clicks, most first
+    pub top_referrers: Vec<(String, u32)>
+}
+
+/// a short url to store
+pub struct NewLink<'a> {
+    pub short: &'a str,
+    pub long: &'a str,
+    /// see `handler::hash_ip`
+    pub ip_hash: &'a str,
+    /// id of the api key used to create it, `None` for passwords
+    pub api_key: Option<i64>,
+    /// local time formatted like `created` (`%Y-%m-%d %H:%M:%S`), `None` never expires
+    pub expires_at: Option<String>,
+    /// the url stops working after this many redirects, `None` for unlimited
+    pub max_redirects: Option<u32>,
+    /// argon2 hash of the passphrase visitors have to enter, `None` for public urls
+    pub passphrase_hash: Option<String>,
+    /// see `Redirect::redirect_code`
+    pub redirect_code: Option<u16>
+}
+
+/// one row of `url_history`, written whenever the target of a short url changes
+pub struct UrlChange {
+    pub id: i64,
+    pub old_long: String,
+    pub new_long: String,
+    pub changed: String,
+    /// who changed it, see `Principal::actor`
+    pub actor: String
+}
 
+/// an issued api key, the key itself is only stored hashed
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// max urls created with this key in 7 days, `None` uses the `max-urls-per-week` setting
+    pub quota: Option<u32>,
+    pub created: String,
+    pub last_used: Option<String>,
+    pub revoked: bool
+}
 
-    fn forward(&mut self, short_url: &str) -> Result<String, ()> {
-        let tx = self.connection.transaction().unwrap();
-        // get url
-        let long: String = tx.query_row(
-            "SELECT long FROM urls WHERE short = ?", &[short_url], |row| row.get(0))
-            .map_err(|_| ())?;
+/// Every worker thread owns its own connection, so implementations only need to be `Send`
+pub trait Database: Send {
+    /// fails with `Conflict` if the short url is taken
+    fn store_shortened(&self, link: &NewLink) -> Result<(), DbError>;
+    /// get the long url and increment counter + update last visited.
+    /// Fails with `Expired` (and counts nothing) if the url expired or reached `max_redirects`.
+    /// The check and increment are atomic, so a one-time url works only once under concurrent access.
+    /// The click and visitor of `visit` are logged together with the increment
+    fn forward(&mut self, short_url: &str, visit: &Visit) -> Result<Redirect, DbError>;
 
-        tx.execute("UPDATE urls SET redirects = redirects + 1, last_redirect = datetime('now', 'localtime') WHERE short = ?", &[short_url])
-            .unwrap();
+    /// the hash of the passphrase visitors need, `None` for public urls.
+    /// Fails with `NotFound` / `Expired` like forward, but counts nothing
+    fn url_passphrase(&self, short_url: &str) -> Result<Option<String>, DbError>;
 
-        tx.commit().unwrap();
-        Ok(long)
-    }
+    /// same as forward, but no increment / update
+    fn peek_long_url(&self, short_url: &str) -> Result<String, DbError>;
+
+    /// read-only statistics of the short url
+    fn url_stats(&self, short_url: &str) -> Result<UrlStats, DbError>;
+
+    /// changes the target of an existing short url and appends the old one to its history
+    fn update_long_url(&self, short_url: &str, long_url: &str, actor: &str) -> Result<(), DbError>;
+
+    /// every change of the target, newest first. Empty for unknown short urls
+    fn url_history(&self, short_url: &str) -> Result<Vec<UrlChange>, DbError>;
+
+    /// counts the logged clicks of a short url since `since` (local time like `created`)
+    fn click_stats(&self, short_url: &str, since: &str) -> Result<ClickStats, DbError>;
+
+    /// deletes logged clicks older than `since`, returns how many
+    fn purge_clicks(&self, since: &str) -> Result<u32, DbError>;
 
-    fn peek_long_url(&self, short_url: &str) -> Result<String, ()> {
-        self.connection.query_row(
-            "SELECT long FROM urls WHERE short = ?", &[short_url], |row| row.get(0))
-            .map_err(|_| ())
+    /// estimated distinct visitors of a short url per day (`%Y-%m-%d`) since `since_day`, oldest first
+    fn daily_unique_visitors(&self, short_url: &str, since_day: &str) -> Result<Vec<(String, u32)>, DbError>;
+
+    /// deletes the visitor sketches of days before `day`, returns how many
+    fn purge_daily_visitors(&self, day: &str) -> Result<u32, DbError>;
+
+    /// The random salt of visitor hashes on `day`, created on first use. Salts of earlier days are deleted,
+    /// so nobody can link the visitors of different days or recompute their hashes
+    fn visitor_salt(&self, day: &str) -> Result<Vec<u8>, DbError>;
+
+    /// removes the short url with its history, clicks and daily visitors, so a new url of the same name
+    /// starts empty. Fails with `NotFound` if it did not exist
+    fn delete_shortened(&self, short_url: &str) -> Result<(), DbError>;
+
+    /// moves all expired or used up urls to `expired_urls`, which frees their short urls.
+    /// Their history, clicks and daily visitors are deleted like in `delete_shortened`. Returns how many
+    fn archive_expired(&self) -> Result<u32, DbError>;
+
+    /// empties the unkeyed `u32` ip hashes of old versions (decimal text) in `urls` and `expired_urls`
+    /// once their urls are older than the 7 days of the quota. Returns how many
+    fn clear_legacy_ip_hashes(&self) -> Result<u32, DbError>;
+
+    /// the stored password called `name`, `NotFound` if there is none
+    fn password_by_name(&self, name: &str) -> Result<String, DbError>;
+
+    /// the passwords from before names: argon2 hashes, or plaintext from before hashing (see `auth::check_password`)
+    fn unnamed_passwords(&self) -> Result<Vec<String>, DbError>;
+
+    /// stores a password hash, optionally under a name. Fails with `Conflict` if the hash or name exists
+    fn add_password(&self, name: Option<&str>, hash: &str) -> Result<(), DbError>;
+
+    /// replaces a stored password (hash), fails with `NotFound` if it does not exist
+    fn replace_password(&self, old: &str, new: &str) -> Result<(), DbError>;
+
+    /// urls created with any of the `ip_hashes` (see `handler::ip_hashes`)
+    fn urls_stored_last_7_days(&self, ip_hashes: &[String]) -> Result<u32, DbError>;
+
+    fn urls_stored_by_key_last_7_days(&self, api_key: i64) -> Result<u32, DbError>;
+
+    /// stores a new api key, fails with `Conflict` if the hash exists. Returns its id
+    fn store_api_key(&self, name: &str, key_hash: &str, scopes: &[Scope], quota: Option<u32>) -> Result<i64, DbError>;
+
+    /// the key with this hash, also if it is revoked
+    fn api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, DbError>;
+
+    /// all keys, also revoked ones, ordered by id
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, DbError>;
+
+    /// sets `last_used` to now
+    fn touch_api_key(&self, id: i64) -> Result<(), DbError>;
+
+    /// revoked keys are kept, so their urls still know who created them
+    fn revoke_api_key(&self, id: i64) -> Result<(), DbError>;
+
+    /// the stored value of a runtime setting (see `config::Settings`)
+    fn get_setting(&self, key: &str) -> Result<Option<String>, DbError>;
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), DbError>;
+}
+
+/// The configured storage, selected by `SHORTY_DB_PATH`
+pub enum Backend {
+    SQLite(String),
+    /// `SHORTY_DB_PATH=:memory:`, shared by all workers
+    Memory(MemoryDB),
+    /// `SHORTY_DB_PATH=postgres://...`, needs the `postgres` feature
+    #[cfg(feature = "postgres")]
+    Postgres(String)
+}
 
+impl Backend {
+    pub fn from_path(path: &str) -> Self {
+        if path == ":memory:" {
+            Backend::Memory(MemoryDB::new())
+        } else if path.starts_with("postgres://") || path.starts_with("postgresql://") {
+            #[cfg(feature = "postgres")]
+            { Backend::Postgres(path.into()) }
+            #[cfg(not(feature = "postgres"))]
+            panic!("SHORTY_DB_PATH is a postgres url, but shorty-rs was built without the postgres feature")
+        } else {
+            Backend::SQLite(path.into())
+        }
     }
 
-    fn is_password(&self, pw: &str) -> bool {
-        self.connection.prepare("SELECT * FROM passwords WHERE password = ?")
-            .unwrap().query(&[pw]).unwrap().next().unwrap().is_some()
+    /// Opens a new connection, every worker owns one
+    pub fn connect(&self) -> Result<Box<dyn Database>, DbError> {
+        match self {
+            Backend::SQLite(path) => SQLiteDB::init_database(path),
+            Backend::Memory(db) => Ok(Box::new(db.clone())),
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(url) => PostgresDB::init_database(url)
+        }
     }
 }
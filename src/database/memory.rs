@@ -0,0 +1,494 @@
+use std::collections::{HashMap, BTreeMap};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
+use chrono::{Local, NaiveDateTime, Duration};
+use super::{Database, DbError, UrlStats, ApiKey, NewLink, Redirect, UrlChange, Visit, ClickStats};
+use crate::auth::Scope;
+use crate::hll::HyperLogLog;
+use rand::Rng;
+
+/// same format as sqlite's `datetime()`
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// the unkeyed `u32` ip hashes of old versions, stored as decimal text
+fn is_legacy_ip_hash(ip_hash: &str) -> bool {
+    !ip_hash.is_empty() && ip_hash.len() <= 10 && ip_hash.bytes().all(|b| b.is_ascii_digit())
+}
+
+struct UrlRow {
+    long: String,
+    ip_hash: String,
+    created: NaiveDateTime,
+    redirects: u32,
+    bot_redirects: u32,
+    last_redirect: NaiveDateTime,
+    api_key: Option<i64>,
+    expires_at: Option<NaiveDateTime>,
+    max_redirects: Option<u32>,
+    passphrase_hash: Option<String>,
+    redirect_code: Option<u16>,
+    visitors: HyperLogLog
+}
+
+impl UrlRow {
+    /// expired or used up, by visitors or bots
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|e| e <= now()) || self.max_redirects.is_some_and(|m| self.redirects + self.bot_redirects >= m)
+    }
+}
+
+/// only what `click_stats` needs, nobody can read the rest of the log
+struct ClickRow {
+    short: String,
+    clicked: NaiveDateTime,
+    referer: Option<String>
+}
+
+struct ApiKeyRow {
+    name: String,
+    key_hash: String,
+    scopes: Vec<Scope>,
+    quota: Option<u32>,
+    created: NaiveDateTime,
+    last_used: Option<NaiveDateTime>,
+    revoked: Option<NaiveDateTime>
+}
+
+impl ApiKeyRow {
+    fn to_api_key(&self, id: i64) -> ApiKey {
+        ApiKey {
+            id,
+            name: self.name.clone(),
+            scopes: self.scopes.clone(),
+            quota: self.quota,
+            created: self.created.format(DATE_FORMAT).to_string(),
+            last_used: self.last_used.map(|d| d.format(DATE_FORMAT).to_string()),
+            revoked: self.revoked.is_some()
+        }
+    }
+}
+
+#[derive(Default)]
+struct MemoryStore {
+    urls: HashMap<String, UrlRow>,
+    /// archived expired / used up urls with the time they were archived
+    expired_urls: Vec<(String, UrlRow, NaiveDateTime)>,
+    /// hash and name
+    passwords: HashMap<String, Option<String>>,
+    settings: HashMap<String, String>,
+    /// ordered by id, which starts at 1
+    api_keys: Vec<ApiKeyRow>,
+    /// short url and change, ordered by id
+    url_history: Vec<(String, UrlChange)>,
+    /// id of the latest change, never reused after its row is deleted (like AUTOINCREMENT)
+    last_history_id: i64,
+    /// ordered by time
+    clicks: Vec<ClickRow>,
+    /// sketches by short url and day (`%Y-%m-%d`)
+    daily_visitors: BTreeMap<(String, String), HyperLogLog>,
+    /// day and salt, only the newest is kept
+    visitor_salt: Option<(String, Vec<u8>)>
+}
+
+impl MemoryStore {
+    /// removes the url with its history, clicks and daily visitors
+    fn remove_url(&mut self, short: &str) -> Option<UrlRow> {
+        let row = self.urls.remove(short)?;
+        self.url_history.retain(|(s, _)| s != short);
+        self.clicks.retain(|c| c.short != short);
+        self.daily_visitors.retain(|(s, _), _| s != short);
+        Some(row)
+    }
+
+    /// `NotFound` for unknown ids, including zero and negative ones
+    fn api_key_mut(&mut self, id: i64) -> Result<&mut ApiKeyRow, DbError> {
+        id.checked_sub(1)
+            .and_then(|idx| usize::try_from(idx).ok())
+            .and_then(move |idx| self.api_keys.get_mut(idx))
+            .ok_or(DbError::NotFound)
+    }
+}
+
+/// Database kept in memory, lost on restart. Used with `SHORTY_DB_PATH=:memory:`.
+/// Clones share the same data, so every worker gets its own clone.
+#[derive(Clone, Default)]
+pub struct MemoryDB {
+    store: Arc<Mutex<MemoryStore>>
+}
+
+impl MemoryDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryStore> {
+        // a panicking worker can't leave a half written row, so the data is still usable
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+impl Database for MemoryDB {
+    fn store_shortened(&self, link: &NewLink) -> Result<(), DbError> {
+        let expires_at = match &link.expires_at {
+            Some(e) => Some(NaiveDateTime::parse_from_str(e, DATE_FORMAT).map_err(|e| DbError::Other(e.to_string()))?),
+            None => None
+        };
+        let mut store = self.lock();
+        if store.urls.contains_key(link.short) {
+            return Err(DbError::Conflict);
+        }
+        store.urls.insert(link.short.into(), UrlRow {
+            long: link.long.into(),
+            ip_hash: link.ip_hash.into(),
+            created: now(),
+            redirects: 0,
+            bot_redirects: 0,
+            last_redirect: now(),
+            api_key: link.api_key,
+            expires_at,
+            max_redirects: link.max_redirects,
+            passphrase_hash: link.passphrase_hash.clone(),
+            redirect_code: link.redirect_code,
+            visitors: HyperLogLog::new()
+        });
+        Ok(())
+    }
+
+    fn forward(&mut self, short_url: &str, visit: &Visit) -> Result<Redirect, DbError> {
+        let mut store = self.lock();
+        let row = store.urls.get_mut(short_url).ok_or(DbError::NotFound)?;
+        if row.is_expired() {
+            return Err(DbError::Expired);
+        }
+        if visit.bot {
+            row.bot_redirects += 1;
+        } else {
+            row.redirects += 1;
+            row.last_redirect = now();
+        }
+        let redirect = Redirect {
+            long: row.long.clone(),
+            redirect_code: row.redirect_code
+        };
+        if let Some(visitor) = visit.visitor {
+            row.visitors.insert(visitor);
+            store.daily_visitors.entry((short_url.into(), now().format("%Y-%m-%d").to_string()))
+                .or_insert_with(HyperLogLog::new)
+                .insert(visitor);
+        }
+        if let Some(click) = &visit.click {
+            store.clicks.push(ClickRow {
+                short: short_url.into(),
+                clicked: now(),
+                referer: click.referer.map(String::from)
+            });
+        }
+        Ok(redirect)
+    }
+
+    fn url_passphrase(&self, short_url: &str) -> Result<Option<String>, DbError> {
+        let store = self.lock();
+        let row = store.urls.get(short_url).ok_or(DbError::NotFound)?;
+        if row.is_expired() {
+            return Err(DbError::Expired);
+        }
+        Ok(row.passphrase_hash.clone())
+    }
+
+    fn peek_long_url(&self, short_url: &str) -> Result<String, DbError> {
+        self.lock().urls.get(short_url).map(|row| row.long.clone()).ok_or(DbError::NotFound)
+    }
+
+    fn url_stats(&self, short_url: &str) -> Result<UrlStats, DbError> {
+        let store = self.lock();
+        let row = store.urls.get(short_url).ok_or(DbError::NotFound)?;
+        Ok(UrlStats {
+            long: row.long.clone(),
+            created: row.created.format(DATE_FORMAT).to_string(),
+            redirects: row.redirects,
+            last_redirect: row.last_redirect.format(DATE_FORMAT).to_string(),
+            expires_at: row.expires_at.map(|e| e.format(DATE_FORMAT).to_string()),
+            max_redirects: row.max_redirects,
+            protected: row.passphrase_hash.is_some(),
+            redirect_code: row.redirect_code,
+            bot_redirects: row.bot_redirects,
+            unique_visitors: row.visitors.estimate()
+        })
+    }
+
+    fn update_long_url(&self, short_url: &str, long_url: &str, actor: &str) -> Result<(), DbError> {
+        let mut store = self.lock();
+        let row = store.urls.get_mut(short_url).ok_or(DbError::NotFound)?;
+        let old_long = std::mem::replace(&mut row.long, long_url.into());
+        store.last_history_id += 1;
+        let id = store.last_history_id;
+        store.url_history.push((short_url.into(), UrlChange {
+            id,
+            old_long,
+            new_long: long_url.into(),
+            changed: now().format(DATE_FORMAT).to_string(),
+            actor: actor.into()
+        }));
+        Ok(())
+    }
+
+    fn url_history(&self, short_url: &str) -> Result<Vec<UrlChange>, DbError> {
+        Ok(self.lock().url_history.iter().rev()
+            .filter(|(short, _)| short == short_url)
+            .map(|(_, c)| UrlChange {
+                id: c.id,
+                old_long: c.old_long.clone(),
+                new_long: c.new_long.clone(),
+                changed: c.changed.clone(),
+                actor: c.actor.clone()
+            })
+            .collect())
+    }
+
+    fn delete_shortened(&self, short_url: &str) -> Result<(), DbError> {
+        self.lock().remove_url(short_url).map(|_| ()).ok_or(DbError::NotFound)
+    }
+
+    fn archive_expired(&self) -> Result<u32, DbError> {
+        let mut store = self.lock();
+        let expired: Vec<String> = store.urls.iter()
+            .filter(|(_, row)| row.is_expired())
+            .map(|(short, _)| short.clone())
+            .collect();
+        for short in &expired {
+            let row = store.remove_url(short).expect("collected above");
+            store.expired_urls.push((short.clone(), row, now()));
+        }
+        Ok(expired.len() as u32)
+    }
+
+    fn clear_legacy_ip_hashes(&self) -> Result<u32, DbError> {
+        let since = now() - Duration::days(7);
+        let mut store = self.lock();
+        let store = &mut *store;
+        let mut cleared = 0;
+        let rows = store.urls.values_mut().chain(store.expired_urls.iter_mut().map(|(_, row, _)| row));
+        for row in rows.filter(|row| row.created <= since && is_legacy_ip_hash(&row.ip_hash)) {
+            row.ip_hash.clear();
+            cleared += 1;
+        }
+        Ok(cleared)
+    }
+
+    fn click_stats(&self, short_url: &str, since: &str) -> Result<ClickStats, DbError> {
+        let since = NaiveDateTime::parse_from_str(since, DATE_FORMAT).map_err(|e| DbError::Other(e.to_string()))?;
+        let store = self.lock();
+        let clicks: Vec<&ClickRow> = store.clicks.iter()
+            .filter(|c| c.short == short_url && c.clicked >= since)
+            .collect();
+        // BTreeMap keeps the keys sorted
+        let count = |key: &dyn Fn(&ClickRow) -> Option<String>| {
+            let mut counts = BTreeMap::new();
+            for click in &clicks {
+                if let Some(key) = key(click) {
+                    *counts.entry(key).or_insert(0u32) += 1;
+                }
+            }
+            counts.into_iter().collect::<Vec<_>>()
+        };
+
+        let mut top_referrers = count(&|c| c.referer.clone());
+        top_referrers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_referrers.truncate(10);
+        Ok(ClickStats {
+            daily: count(&|c| Some(c.clicked.format("%Y-%m-%d").to_string())),
+            hourly: count(&|c| Some(c.clicked.format("%Y-%m-%d %H:00").to_string())),
+            top_referrers
+        })
+    }
+
+    fn purge_clicks(&self, since: &str) -> Result<u32, DbError> {
+        let since = NaiveDateTime::parse_from_str(since, DATE_FORMAT).map_err(|e| DbError::Other(e.to_string()))?;
+        let mut store = self.lock();
+        let before = store.clicks.len();
+        store.clicks.retain(|c| c.clicked >= since);
+        Ok((before - store.clicks.len()) as u32)
+    }
+
+    fn daily_unique_visitors(&self, short_url: &str, since_day: &str) -> Result<Vec<(String, u32)>, DbError> {
+        Ok(self.lock().daily_visitors.iter()
+            .filter(|((short, day), _)| short == short_url && day.as_str() >= since_day)
+            .map(|((_, day), sketch)| (day.clone(), sketch.estimate()))
+            .collect())
+    }
+
+    fn purge_daily_visitors(&self, day: &str) -> Result<u32, DbError> {
+        let mut store = self.lock();
+        let before = store.daily_visitors.len();
+        store.daily_visitors.retain(|(_, d), _| d.as_str() >= day);
+        Ok((before - store.daily_visitors.len()) as u32)
+    }
+
+    fn visitor_salt(&self, day: &str) -> Result<Vec<u8>, DbError> {
+        let mut store = self.lock();
+        match &store.visitor_salt {
+            Some((d, salt)) if d == day => Ok(salt.clone()),
+            _ => {
+                let salt = rand::thread_rng().gen::<[u8; 32]>().to_vec();
+                store.visitor_salt = Some((day.into(), salt.clone()));
+                Ok(salt)
+            }
+        }
+    }
+
+    fn password_by_name(&self, name: &str) -> Result<String, DbError> {
+        self.lock().passwords.iter()
+            .find(|(_, n)| n.as_deref() == Some(name))
+            .map(|(hash, _)| hash.clone())
+            .ok_or(DbError::NotFound)
+    }
+
+    fn unnamed_passwords(&self) -> Result<Vec<String>, DbError> {
+        Ok(self.lock().passwords.iter().filter(|(_, n)| n.is_none()).map(|(hash, _)| hash.clone()).collect())
+    }
+
+    fn add_password(&self, name: Option<&str>, hash: &str) -> Result<(), DbError> {
+        let mut store = self.lock();
+        if store.passwords.contains_key(hash) || (name.is_some() && store.passwords.values().any(|n| n.as_deref() == name)) {
+            return Err(DbError::Conflict);
+        }
+        store.passwords.insert(hash.into(), name.map(String::from));
+        Ok(())
+    }
+
+    fn replace_password(&self, old: &str, new: &str) -> Result<(), DbError> {
+        let mut store = self.lock();
+        let name = store.passwords.remove(old).ok_or(DbError::NotFound)?;
+        store.passwords.insert(new.into(), name);
+        Ok(())
+    }
+
+    fn urls_stored_last_7_days(&self, ip_hashes: &[String]) -> Result<u32, DbError> {
+        let since = now() - Duration::days(7);
+        Ok(self.lock().urls.values()
+            .filter(|row| ip_hashes.contains(&row.ip_hash) && row.created > since)
+            .count() as u32)
+    }
+
+    fn urls_stored_by_key_last_7_days(&self, api_key: i64) -> Result<u32, DbError> {
+        let since = now() - Duration::days(7);
+        Ok(self.lock().urls.values()
+            .filter(|row| row.api_key == Some(api_key) && row.created > since)
+            .count() as u32)
+    }
+
+    fn store_api_key(&self, name: &str, key_hash: &str, scopes: &[Scope], quota: Option<u32>) -> Result<i64, DbError> {
+        let mut store = self.lock();
+        if store.api_keys.iter().any(|k| k.key_hash == key_hash) {
+            return Err(DbError::Conflict);
+        }
+        store.api_keys.push(ApiKeyRow {
+            name: name.into(),
+            key_hash: key_hash.into(),
+            scopes: scopes.to_vec(),
+            quota,
+            created: now(),
+            last_used: None,
+            revoked: None
+        });
+        Ok(store.api_keys.len() as i64)
+    }
+
+    fn api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, DbError> {
+        self.lock().api_keys.iter().enumerate()
+            .find(|(_, k)| k.key_hash == key_hash)
+            .map(|(idx, k)| k.to_api_key(idx as i64 + 1))
+            .ok_or(DbError::NotFound)
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, DbError> {
+        Ok(self.lock().api_keys.iter().enumerate()
+            .map(|(idx, k)| k.to_api_key(idx as i64 + 1))
+            .collect())
+    }
+
+    fn touch_api_key(&self, id: i64) -> Result<(), DbError> {
+        let mut store = self.lock();
+        let key = store.api_key_mut(id)?;
+        key.last_used = Some(now());
+        Ok(())
+    }
+
+    fn revoke_api_key(&self, id: i64) -> Result<(), DbError> {
+        let mut store = self.lock();
+        let key = store.api_key_mut(id)?;
+        key.revoked.get_or_insert_with(now);
+        Ok(())
+    }
+
+    fn get_setting(&self, key: &str) -> Result<Option<String>, DbError> {
+        Ok(self.lock().settings.get(key).cloned())
+    }
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), DbError> {
+        self.lock().settings.insert(key.into(), value.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Click;
+
+    fn link<'a>(short: &'a str, long: &'a str) -> NewLink<'a> {
+        NewLink {
+            short,
+            long,
+            ip_hash: "test",
+            api_key: None,
+            expires_at: None,
+            max_redirects: None,
+            passphrase_hash: None,
+            redirect_code: None
+        }
+    }
+
+    #[test]
+    fn deleted_urls_leave_no_rows() {
+        let mut db = MemoryDB::new();
+        db.store_shortened(&link("reuse", "https://old.org")).unwrap();
+        db.update_long_url("reuse", "https://old2.org", "password").unwrap();
+        let click = Click { ip_hash: "test".into(), referer: Some("https://ref.org"), user_agent: None, accept_language: None };
+        db.forward("reuse", &Visit { bot: false, click: Some(click), visitor: Some(42) }).unwrap();
+        db.delete_shortened("reuse").unwrap();
+
+        db.store_shortened(&link("reuse", "https://new.org")).unwrap();
+        assert!(db.url_history("reuse").unwrap().is_empty());
+        let stats = db.click_stats("reuse", "2000-01-01 00:00:00").unwrap();
+        assert!(stats.daily.is_empty() && stats.top_referrers.is_empty());
+        assert!(db.daily_unique_visitors("reuse", "2000-01-01").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_api_key_ids_are_not_found() {
+        let db = MemoryDB::new();
+        let id = db.store_api_key("test", "hash", &[Scope::Create], None).unwrap();
+        for unknown in [0, -1, i64::MIN, id + 1, i64::MAX] {
+            assert!(matches!(db.revoke_api_key(unknown), Err(DbError::NotFound)), "{}", unknown);
+            assert!(matches!(db.touch_api_key(unknown), Err(DbError::NotFound)), "{}", unknown);
+        }
+        db.revoke_api_key(id).unwrap();
+        assert!(db.api_key_by_hash("hash").unwrap().revoked);
+    }
+
+    #[test]
+    fn bots_use_up_one_time_links() {
+        let mut db = MemoryDB::new();
+        db.store_shortened(&NewLink { max_redirects: Some(1), ..link("once", "https://once.org") }).unwrap();
+        let bot = Visit { bot: true, click: None, visitor: None };
+        db.forward("once", &bot).unwrap();
+        assert!(matches!(db.forward("once", &bot), Err(DbError::Expired)));
+        assert!(matches!(db.forward("once", &Visit { bot: false, click: None, visitor: None }), Err(DbError::Expired)));
+        assert_eq!(db.archive_expired().unwrap(), 1);
+    }
+}
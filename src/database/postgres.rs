@@ -0,0 +1,599 @@
+use postgres::{Client, NoTls, Row, error::SqlState};
+use std::cell::RefCell;
+use crate::log;
+use super::{Database, DbError, UrlStats, ApiKey, NewLink, Redirect, UrlChange, Visit, ClickStats};
+use crate::auth::Scope;
+use crate::hll::{self, HyperLogLog};
+use rand::Rng;
+
+
+impl From<postgres::Error> for DbError {
+    fn from(e: postgres::Error) -> Self {
+        match e.code() {
+            Some(code) if *code == SqlState::UNIQUE_VIOLATION => DbError::Conflict,
+            Some(code) if *code == SqlState::LOCK_NOT_AVAILABLE || *code == SqlState::T_R_SERIALIZATION_FAILURE ||
+                *code == SqlState::T_R_DEADLOCK_DETECTED || *code == SqlState::TOO_MANY_CONNECTIONS => DbError::Busy,
+            Some(code) if *code == SqlState::DATA_CORRUPTED || *code == SqlState::INDEX_CORRUPTED => DbError::Corrupt,
+            _ => DbError::Other(e.to_string())
+        }
+    }
+}
+
+
+/// Database on a (shared) postgres server, selected by a `postgres://` url in `SHORTY_DB_PATH`
+pub struct PostgresDB {
+    // the trait takes `&self` for reads, but the client needs `&mut`
+    client: RefCell<Client>
+}
+
+/// Same schema versions as `SQLITE_MIGRATIONS`, in postgres syntax.
+/// Only append new migrations, released ones must never change.
+const POSTGRES_MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE IF NOT EXISTS urls (
+        short TEXT NOT NULL PRIMARY KEY,
+        long TEXT NOT NULL,
+        ip_hash BIGINT NOT NULL,
+        created TIMESTAMP(0) NOT NULL,
+        redirects INTEGER NOT NULL,
+        last_redirect TIMESTAMP(0) NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS passwords (
+        password TEXT NOT NULL PRIMARY KEY
+    );",
+    // 2: runtime settings
+    "CREATE TABLE IF NOT EXISTS settings (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+    // 3: scoped api keys
+    "CREATE TABLE api_keys (
+        id BIGSERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        scopes TEXT NOT NULL,
+        quota INTEGER,
+        created TIMESTAMP(0) NOT NULL,
+        last_used TIMESTAMP(0),
+        revoked TIMESTAMP(0)
+    );
+    ALTER TABLE urls ADD COLUMN api_key BIGINT REFERENCES api_keys(id);",
+    // 4: link expiration
+    "ALTER TABLE urls ADD COLUMN expires_at TIMESTAMP(0);
+    CREATE INDEX urls_expires_at ON urls(expires_at);
+    CREATE TABLE expired_urls (
+        short TEXT NOT NULL,
+        long TEXT NOT NULL,
+        ip_hash BIGINT NOT NULL,
+        created TIMESTAMP(0) NOT NULL,
+        redirects INTEGER NOT NULL,
+        last_redirect TIMESTAMP(0) NOT NULL,
+        api_key BIGINT,
+        expires_at TIMESTAMP(0) NOT NULL,
+        archived TIMESTAMP(0) NOT NULL
+    );",
+    // 5: click limited urls, used up urls are archived without expires_at
+    "ALTER TABLE urls ADD COLUMN max_redirects INTEGER;
+    ALTER TABLE expired_urls ALTER COLUMN expires_at DROP NOT NULL, ADD COLUMN max_redirects INTEGER;",
+    // 6: passphrase protected urls
+    "ALTER TABLE urls ADD COLUMN passphrase_hash TEXT;",
+    // 7: redirect status per url
+    "ALTER TABLE urls ADD COLUMN redirect_code INTEGER;",
+    // 8: append-only log of target changes
+    "CREATE TABLE url_history (
+        id BIGSERIAL PRIMARY KEY,
+        short TEXT NOT NULL,
+        old_long TEXT NOT NULL,
+        new_long TEXT NOT NULL,
+        changed TIMESTAMP(0) NOT NULL,
+        actor TEXT NOT NULL
+    );
+    CREATE INDEX url_history_short ON url_history(short);",
+    // 9: click log
+    "CREATE TABLE clicks (
+        short TEXT NOT NULL,
+        clicked TIMESTAMP(0) NOT NULL,
+        ip_hash BIGINT NOT NULL,
+        referer TEXT,
+        user_agent TEXT,
+        accept_language TEXT
+    );
+    CREATE INDEX clicks_short_clicked ON clicks(short, clicked);
+    CREATE INDEX clicks_clicked ON clicks(clicked);",
+    // 10: visits of bots are counted separately
+    "ALTER TABLE urls ADD COLUMN bot_redirects INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE expired_urls ADD COLUMN bot_redirects INTEGER NOT NULL DEFAULT 0;",
+    // 11: unique visitor sketches, see `hll`
+    "ALTER TABLE urls ADD COLUMN visitors BYTEA;
+    CREATE TABLE daily_visitors (
+        short TEXT NOT NULL,
+        day DATE NOT NULL,
+        registers BYTEA NOT NULL,
+        PRIMARY KEY(short, day)
+    );
+    CREATE TABLE visitor_salts (day DATE NOT NULL PRIMARY KEY, salt BYTEA NOT NULL);",
+    // 12: keyed ip hashes are stored as hex, the old u32 hashes become decimal text
+    "ALTER TABLE urls ALTER COLUMN ip_hash TYPE TEXT USING ip_hash::TEXT;
+    ALTER TABLE expired_urls ALTER COLUMN ip_hash TYPE TEXT USING ip_hash::TEXT;
+    ALTER TABLE clicks ALTER COLUMN ip_hash TYPE TEXT USING ip_hash::TEXT;
+    CREATE INDEX urls_ip_hash ON urls(ip_hash, created);",
+    // 13: named passwords, so a login checks one hash instead of all of them. Old rows stay unnamed
+    "ALTER TABLE passwords ADD COLUMN name TEXT UNIQUE;"
+];
+
+/// urls that stopped working. Bots use up `max_redirects` as well
+const EXPIRED_CONDITION: &str = "expires_at <= LOCALTIMESTAMP(0) OR redirects + bot_redirects >= max_redirects";
+
+/// the unkeyed `u32` ip hashes of old versions, decimal text since migration 12
+const LEGACY_IP_HASH: &str = "ip_hash ~ '^[0-9]{1,10}$'";
+
+/// tables with rows of a short url, which are deleted with it
+const LINK_TABLES: [&str; 3] = ["url_history", "clicks", "daily_visitors"];
+
+/// more CTEs deleting the rows of `LINK_TABLES` of the short urls the CTE `removed` returns
+fn delete_link_rows(removed: &str) -> String {
+    LINK_TABLES.iter()
+        .map(|table| format!(", {0}_deleted AS (DELETE FROM {0} WHERE short IN (SELECT short FROM {1}))", table, removed))
+        .collect()
+}
+
+/// key of the advisory lock held while migrating, so only one instance migrates
+const MIGRATION_LOCK: i64 = 0x73686f727479;
+
+/// timestamps are formatted like sqlite's `datetime()`
+const DATE_FORMAT: &str = "YYYY-MM-DD HH24:MI:SS";
+
+/// `$1` is the `DATE_FORMAT`
+const API_KEY_COLUMNS: &str = "id, name, scopes, quota, to_char(created, $1), to_char(last_used, $1), revoked IS NOT NULL";
+
+fn api_key_from_row(row: &Row) -> ApiKey {
+    ApiKey {
+        id: row.get(0),
+        name: row.get(1),
+        scopes: Scope::parse_list(row.get(2)),
+        quota: row.get::<_, Option<i32>>(3).map(|q| q as u32),
+        created: row.get(4),
+        last_used: row.get(5),
+        revoked: row.get(6)
+    }
+}
+
+impl PostgresDB {
+    pub fn init_database(url: &str) -> Result<Box<dyn Database>, DbError> {
+        let mut client = Client::connect(url, NoTls)?;
+        Self::migrate(&mut client)?;
+        Ok(Box::new(PostgresDB { client: RefCell::new(client) }))
+    }
+
+    /// Creates the schema or upgrades it to the newest version
+    fn migrate(client: &mut Client) -> Result<(), DbError> {
+        let mut tx = client.transaction()?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])?;
+        tx.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER NOT NULL PRIMARY KEY, applied TIMESTAMP(0) NOT NULL)"
+        )?;
+        let version: i32 = tx.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[])?.get(0);
+
+        for (idx, migration) in POSTGRES_MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let new_version = idx as i32 + 1;
+            log(format!("Migrating database to schema version {}", new_version));
+            tx.batch_execute(migration)?;
+            tx.execute("INSERT INTO schema_migrations (version, applied) VALUES ($1, LOCALTIMESTAMP(0))", &[&new_version])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+
+impl Database for PostgresDB {
+    fn store_shortened(&self, link: &NewLink) -> Result<(), DbError> {
+        self.client.borrow_mut().execute(
+            "INSERT INTO urls (short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, max_redirects, \
+            passphrase_hash, redirect_code) \
+            VALUES ($1, $2, $3, LOCALTIMESTAMP(0), 0, LOCALTIMESTAMP(0), $4, $5::TEXT::TIMESTAMP(0), $6, $7, $8)",
+            &[&link.short, &link.long, &link.ip_hash, &link.api_key, &link.expires_at,
+                &link.max_redirects.map(|m| m as i32), &link.passphrase_hash, &link.redirect_code.map(|c| c as i32)]
+        )?;
+        Ok(())
+    }
+
+    fn forward(&mut self, short_url: &str, visit: &Visit) -> Result<Redirect, DbError> {
+        // one statement, so concurrent redirects are counted (and logged) atomically.
+        // A waiting update rechecks the condition on the updated row, so `max_redirects` can't be exceeded.
+        // The sketches are updated register by register for the same reason
+        let client = self.client.get_mut();
+        let counter = if visit.bot {
+            "bot_redirects = bot_redirects + 1"
+        } else {
+            "redirects = redirects + 1, last_redirect = LOCALTIMESTAMP(0)"
+        };
+        let empty_sketch = format!("decode(repeat('00', {}), 'hex')", hll::REGISTERS);
+        let click = visit.click.as_ref();
+        let (register, rank) = match visit.visitor.map(HyperLogLog::position) {
+            Some((register, rank)) => (Some(register as i32), Some(rank as i32)),
+            None => (None, None)
+        };
+        let row = client.query_opt(
+            format!("WITH forwarded AS (\
+                UPDATE urls SET {counter}, visitors = CASE WHEN $7::INTEGER IS NULL THEN visitors ELSE \
+                    set_byte(COALESCE(visitors, {empty}), $7, GREATEST(get_byte(COALESCE(visitors, {empty}), $7), $8::INTEGER)) END \
+                WHERE short = $1 AND NOT COALESCE({expired}, FALSE) RETURNING short, long, redirect_code, last_redirect), \
+            logged AS (\
+                INSERT INTO clicks (short, clicked, ip_hash, referer, user_agent, accept_language) \
+                SELECT short, last_redirect, $3, $4, $5, $6 FROM forwarded WHERE $2), \
+            counted AS (\
+                INSERT INTO daily_visitors (short, day, registers) \
+                SELECT short, CURRENT_DATE, set_byte({empty}, $7, $8) FROM forwarded WHERE $7 IS NOT NULL \
+                ON CONFLICT (short, day) DO UPDATE \
+                SET registers = set_byte(daily_visitors.registers, $7, GREATEST(get_byte(daily_visitors.registers, $7), $8))) \
+            SELECT long, redirect_code FROM forwarded", counter = counter, empty = empty_sketch, expired = EXPIRED_CONDITION).as_str(),
+            &[&short_url, &click.is_some(), &click.map(|c| &c.ip_hash), &click.and_then(|c| c.referer),
+                &click.and_then(|c| c.user_agent), &click.and_then(|c| c.accept_language), &register, &rank]
+        )?;
+        match row {
+            Some(row) => Ok(Redirect {
+                long: row.get(0),
+                redirect_code: row.get::<_, Option<i32>>(1).map(|c| c as u16)
+            }),
+            None => match client.query_opt("SELECT 1 FROM urls WHERE short = $1", &[&short_url])? {
+                Some(_) => Err(DbError::Expired),
+                None => Err(DbError::NotFound)
+            }
+        }
+    }
+
+    fn url_passphrase(&self, short_url: &str) -> Result<Option<String>, DbError> {
+        let row = self.client.borrow_mut().query_opt(
+            format!("SELECT passphrase_hash, COALESCE({}, FALSE) FROM urls WHERE short = $1", EXPIRED_CONDITION).as_str(),
+            &[&short_url]
+        )?.ok_or(DbError::NotFound)?;
+        if row.get(1) {
+            return Err(DbError::Expired);
+        }
+        Ok(row.get(0))
+    }
+
+    fn peek_long_url(&self, short_url: &str) -> Result<String, DbError> {
+        let row = self.client.borrow_mut().query_opt("SELECT long FROM urls WHERE short = $1", &[&short_url])?
+            .ok_or(DbError::NotFound)?;
+        Ok(row.get(0))
+    }
+
+    fn url_stats(&self, short_url: &str) -> Result<UrlStats, DbError> {
+        let row = self.client.borrow_mut().query_opt(
+            "SELECT long, to_char(created, $2), redirects, to_char(last_redirect, $2), to_char(expires_at, $2), \
+            max_redirects, passphrase_hash IS NOT NULL, redirect_code, bot_redirects, visitors FROM urls WHERE short = $1",
+            &[&short_url, &DATE_FORMAT]
+        )?.ok_or(DbError::NotFound)?;
+        Ok(UrlStats {
+            long: row.get(0),
+            created: row.get(1),
+            redirects: row.get::<_, i32>(2) as u32,
+            last_redirect: row.get(3),
+            expires_at: row.get(4),
+            max_redirects: row.get::<_, Option<i32>>(5).map(|m| m as u32),
+            protected: row.get(6),
+            redirect_code: row.get::<_, Option<i32>>(7).map(|c| c as u16),
+            bot_redirects: row.get::<_, i32>(8) as u32,
+            unique_visitors: row.get::<_, Option<Vec<u8>>>(9).map_or(0, |v| HyperLogLog::from_bytes(&v).estimate())
+        })
+    }
+
+    fn update_long_url(&self, short_url: &str, long_url: &str, actor: &str) -> Result<(), DbError> {
+        // the locked subquery returns the target from before the update
+        match self.client.borrow_mut().execute(
+            "WITH changed AS (\
+                UPDATE urls SET long = $2 FROM (SELECT short, long FROM urls WHERE short = $1 FOR UPDATE) old \
+                WHERE urls.short = old.short RETURNING old.long AS old_long) \
+            INSERT INTO url_history (short, old_long, new_long, changed, actor) \
+            SELECT $1, old_long, $2, LOCALTIMESTAMP(0), $3 FROM changed",
+            &[&short_url, &long_url, &actor]
+        )? {
+            0 => Err(DbError::NotFound),
+            _ => Ok(())
+        }
+    }
+
+    fn url_history(&self, short_url: &str) -> Result<Vec<UrlChange>, DbError> {
+        let rows = self.client.borrow_mut().query(
+            "SELECT id, old_long, new_long, to_char(changed, $2), actor FROM url_history WHERE short = $1 ORDER BY id DESC",
+            &[&short_url, &DATE_FORMAT]
+        )?;
+        Ok(rows.iter().map(|row| UrlChange {
+            id: row.get(0),
+            old_long: row.get(1),
+            new_long: row.get(2),
+            changed: row.get(3),
+            actor: row.get(4)
+        }).collect())
+    }
+
+    fn delete_shortened(&self, short_url: &str) -> Result<(), DbError> {
+        // one statement, so the rows of the url are deleted atomically with it
+        let deleted: i64 = self.client.borrow_mut().query_one(
+            format!("WITH deleted AS (DELETE FROM urls WHERE short = $1 RETURNING short){} \
+            SELECT COUNT(*) FROM deleted", delete_link_rows("deleted")).as_str(), &[&short_url])?.get(0);
+        match deleted {
+            0 => Err(DbError::NotFound),
+            _ => Ok(())
+        }
+    }
+
+    fn archive_expired(&self) -> Result<u32, DbError> {
+        let archived = self.client.borrow_mut().execute(
+            format!("WITH moved AS (DELETE FROM urls WHERE {} RETURNING *){} \
+            INSERT INTO expired_urls \
+            (short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, max_redirects, bot_redirects, archived) \
+            SELECT short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, max_redirects, bot_redirects, \
+            LOCALTIMESTAMP(0) FROM moved", EXPIRED_CONDITION, delete_link_rows("moved")).as_str(), &[])?;
+        Ok(archived as u32)
+    }
+
+    fn clear_legacy_ip_hashes(&self) -> Result<u32, DbError> {
+        let mut client = self.client.borrow_mut();
+        let mut cleared = 0;
+        for table in &["urls", "expired_urls"] {
+            cleared += client.execute(format!(
+                "UPDATE {} SET ip_hash = '' WHERE {} AND created <= LOCALTIMESTAMP(0) - INTERVAL '7 days'",
+                table, LEGACY_IP_HASH).as_str(), &[])?;
+        }
+        Ok(cleared as u32)
+    }
+
+    fn click_stats(&self, short_url: &str, since: &str) -> Result<ClickStats, DbError> {
+        let mut client = self.client.borrow_mut();
+        let mut counts = |sql: &str| -> Result<Vec<(String, u32)>, DbError> {
+            let rows = client.query(sql, &[&short_url, &since])?;
+            Ok(rows.iter().map(|row| (row.get(0), row.get::<_, i64>(1) as u32)).collect())
+        };
+        Ok(ClickStats {
+            daily: counts("SELECT to_char(clicked, 'YYYY-MM-DD') AS day, COUNT(*) FROM clicks \
+                WHERE short = $1 AND clicked >= $2::TEXT::TIMESTAMP(0) GROUP BY day ORDER BY day")?,
+            hourly: counts("SELECT to_char(clicked, 'YYYY-MM-DD HH24:00') AS hour, COUNT(*) FROM clicks \
+                WHERE short = $1 AND clicked >= $2::TEXT::TIMESTAMP(0) GROUP BY hour ORDER BY hour")?,
+            top_referrers: counts("SELECT referer, COUNT(*) AS n FROM clicks \
+                WHERE short = $1 AND clicked >= $2::TEXT::TIMESTAMP(0) AND referer IS NOT NULL \
+                GROUP BY referer ORDER BY n DESC, referer LIMIT 10")?
+        })
+    }
+
+    fn purge_clicks(&self, since: &str) -> Result<u32, DbError> {
+        Ok(self.client.borrow_mut().execute("DELETE FROM clicks WHERE clicked < $1::TEXT::TIMESTAMP(0)", &[&since])? as u32)
+    }
+
+    fn daily_unique_visitors(&self, short_url: &str, since_day: &str) -> Result<Vec<(String, u32)>, DbError> {
+        let rows = self.client.borrow_mut().query(
+            "SELECT to_char(day, 'YYYY-MM-DD'), registers FROM daily_visitors \
+            WHERE short = $1 AND day >= $2::TEXT::DATE ORDER BY day",
+            &[&short_url, &since_day]
+        )?;
+        Ok(rows.iter().map(|row| (row.get(0), HyperLogLog::from_bytes(row.get(1)).estimate())).collect())
+    }
+
+    fn purge_daily_visitors(&self, day: &str) -> Result<u32, DbError> {
+        Ok(self.client.borrow_mut().execute("DELETE FROM daily_visitors WHERE day < $1::TEXT::DATE", &[&day])? as u32)
+    }
+
+    fn visitor_salt(&self, day: &str) -> Result<Vec<u8>, DbError> {
+        // another instance may have created it meanwhile, then its salt is kept
+        let salt: [u8; 32] = rand::thread_rng().gen();
+        let mut client = self.client.borrow_mut();
+        client.execute(
+            "INSERT INTO visitor_salts (day, salt) VALUES ($1::TEXT::DATE, $2) ON CONFLICT (day) DO NOTHING",
+            &[&day, &&salt[..]]
+        )?;
+        client.execute("DELETE FROM visitor_salts WHERE day < $1::TEXT::DATE", &[&day])?;
+        Ok(client.query_one("SELECT salt FROM visitor_salts WHERE day = $1::TEXT::DATE", &[&day])?.get(0))
+    }
+
+    fn password_by_name(&self, name: &str) -> Result<String, DbError> {
+        match self.client.borrow_mut().query_opt("SELECT password FROM passwords WHERE name = $1", &[&name])? {
+            Some(row) => Ok(row.get(0)),
+            None => Err(DbError::NotFound)
+        }
+    }
+
+    fn unnamed_passwords(&self) -> Result<Vec<String>, DbError> {
+        Ok(self.client.borrow_mut().query("SELECT password FROM passwords WHERE name IS NULL", &[])?
+            .iter().map(|row| row.get(0)).collect())
+    }
+
+    fn add_password(&self, name: Option<&str>, hash: &str) -> Result<(), DbError> {
+        self.client.borrow_mut().execute("INSERT INTO passwords (name, password) VALUES ($1, $2)", &[&name, &hash])?;
+        Ok(())
+    }
+
+    fn replace_password(&self, old: &str, new: &str) -> Result<(), DbError> {
+        match self.client.borrow_mut().execute("UPDATE passwords SET password = $2 WHERE password = $1", &[&old, &new])? {
+            0 => Err(DbError::NotFound),
+            _ => Ok(())
+        }
+    }
+
+    fn urls_stored_last_7_days(&self, ip_hashes: &[String]) -> Result<u32, DbError> {
+        let row = self.client.borrow_mut().query_one(
+            "SELECT COUNT(*) FROM urls WHERE ip_hash = ANY($1) AND created > LOCALTIMESTAMP(0) - INTERVAL '7 days'",
+            &[&ip_hashes]
+        )?;
+        Ok(row.get::<_, i64>(0) as u32)
+    }
+
+    fn urls_stored_by_key_last_7_days(&self, api_key: i64) -> Result<u32, DbError> {
+        let row = self.client.borrow_mut().query_one(
+            "SELECT COUNT(*) FROM urls WHERE api_key = $1 AND created > LOCALTIMESTAMP(0) - INTERVAL '7 days'",
+            &[&api_key]
+        )?;
+        Ok(row.get::<_, i64>(0) as u32)
+    }
+
+    fn store_api_key(&self, name: &str, key_hash: &str, scopes: &[Scope], quota: Option<u32>) -> Result<i64, DbError> {
+        let row = self.client.borrow_mut().query_one(
+            "INSERT INTO api_keys (name, key_hash, scopes, quota, created) VALUES ($1, $2, $3, $4, LOCALTIMESTAMP(0)) RETURNING id",
+            &[&name, &key_hash, &Scope::join(scopes), &quota.map(|q| q as i32)]
+        )?;
+        Ok(row.get(0))
+    }
+
+    fn api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, DbError> {
+        let row = self.client.borrow_mut().query_opt(
+            format!("SELECT {} FROM api_keys WHERE key_hash = $2", API_KEY_COLUMNS).as_str(),
+            &[&DATE_FORMAT, &key_hash]
+        )?.ok_or(DbError::NotFound)?;
+        Ok(api_key_from_row(&row))
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, DbError> {
+        let rows = self.client.borrow_mut().query(
+            format!("SELECT {} FROM api_keys ORDER BY id", API_KEY_COLUMNS).as_str(), &[&DATE_FORMAT])?;
+        Ok(rows.iter().map(api_key_from_row).collect())
+    }
+
+    fn touch_api_key(&self, id: i64) -> Result<(), DbError> {
+        self.client.borrow_mut().execute("UPDATE api_keys SET last_used = LOCALTIMESTAMP(0) WHERE id = $1", &[&id])?;
+        Ok(())
+    }
+
+    fn revoke_api_key(&self, id: i64) -> Result<(), DbError> {
+        match self.client.borrow_mut().execute(
+            "UPDATE api_keys SET revoked = COALESCE(revoked, LOCALTIMESTAMP(0)) WHERE id = $1", &[&id])? {
+            0 => Err(DbError::NotFound),
+            _ => Ok(())
+        }
+    }
+
+    fn get_setting(&self, key: &str) -> Result<Option<String>, DbError> {
+        Ok(self.client.borrow_mut().query_opt("SELECT value FROM settings WHERE key = $1", &[&key])?
+            .map(|row| row.get(0)))
+    }
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), DbError> {
+        self.client.borrow_mut().execute(
+            "INSERT INTO settings (key, value) VALUES ($1, $2) ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+            &[&key, &value]
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Click;
+
+    /// a server to test against, e.g. `postgres://postgres@localhost/shorty_test`.
+    /// The tests are ignored unless run with `--ignored`, which needs it
+    fn test_url() -> String {
+        std::env::var("SHORTY_TEST_PG_URL").ok().filter(|u| !u.is_empty())
+            .expect("SHORTY_TEST_PG_URL must be set to run the postgres tests")
+    }
+
+    /// unique per run, so runs don't see each other's rows
+    fn unique(prefix: &str) -> String {
+        format!("{}{}", prefix, rand::thread_rng().gen::<u32>())
+    }
+
+    fn link<'a>(short: &'a str, long: &'a str, max_redirects: Option<u32>) -> NewLink<'a> {
+        NewLink {
+            short,
+            long,
+            ip_hash: "test",
+            api_key: None,
+            expires_at: None,
+            max_redirects,
+            passphrase_hash: None,
+            redirect_code: Some(302)
+        }
+    }
+
+    fn visit() -> Visit<'static> {
+        let click = Click { ip_hash: "test".into(), referer: Some("https://ref.org"), user_agent: None, accept_language: None };
+        Visit { bot: false, click: Some(click), visitor: Some(42) }
+    }
+
+    #[test]
+    #[ignore = "needs a postgres server in SHORTY_TEST_PG_URL"]
+    fn stores_forwards_and_deletes_links() {
+        let url = test_url();
+        let mut db = PostgresDB::init_database(&url).unwrap();
+        let short = unique("pgtest");
+
+        db.store_shortened(&link(&short, "https://old.org", None)).unwrap();
+        assert!(matches!(db.store_shortened(&link(&short, "https://other.org", None)), Err(DbError::Conflict)));
+        let redirect = db.forward(&short, &visit()).unwrap();
+        assert_eq!((redirect.long.as_str(), redirect.redirect_code), ("https://old.org", Some(302)));
+        db.update_long_url(&short, "https://new.org", "password").unwrap();
+        assert_eq!(db.url_history(&short).unwrap().len(), 1);
+        assert_eq!(db.url_stats(&short).unwrap().redirects, 1);
+
+        db.delete_shortened(&short).unwrap();
+        assert!(matches!(db.delete_shortened(&short), Err(DbError::NotFound)));
+        db.store_shortened(&link(&short, "https://reused.org", None)).unwrap();
+        assert!(db.url_history(&short).unwrap().is_empty());
+        assert!(db.click_stats(&short, "2000-01-01 00:00:00").unwrap().top_referrers.is_empty());
+        assert!(db.daily_unique_visitors(&short, "2000-01-01").unwrap().is_empty());
+        db.delete_shortened(&short).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a postgres server in SHORTY_TEST_PG_URL"]
+    fn counts_one_time_links_once() {
+        let url = test_url();
+        let db = PostgresDB::init_database(&url).unwrap();
+        let short = unique("pgonce");
+        db.store_shortened(&link(&short, "https://once.org", Some(1))).unwrap();
+
+        // concurrent visits on their own connections, like the workers
+        let forwarded = std::thread::scope(|s| {
+            let visits: Vec<_> = (0..4).map(|_| s.spawn(|| {
+                let mut db = PostgresDB::init_database(&url).unwrap();
+                db.forward(&short, &visit()).is_ok()
+            })).collect();
+            visits.into_iter().map(|v| v.join().unwrap()).filter(|&ok| ok).count()
+        });
+        assert_eq!(forwarded, 1);
+        db.delete_shortened(&short).unwrap();
+
+        // bots use it up as well
+        db.store_shortened(&link(&short, "https://once.org", Some(1))).unwrap();
+        let mut db = PostgresDB::init_database(&url).unwrap();
+        let bot = Visit { bot: true, click: None, visitor: None };
+        db.forward(&short, &bot).unwrap();
+        assert!(matches!(db.forward(&short, &bot), Err(DbError::Expired)));
+        assert!(matches!(db.forward(&short, &visit()), Err(DbError::Expired)));
+        db.delete_shortened(&short).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a postgres server in SHORTY_TEST_PG_URL"]
+    fn finds_passwords_by_name() {
+        let url = test_url();
+        let db = PostgresDB::init_database(&url).unwrap();
+        let name = unique("pgtest");
+        let hash = unique("hash");
+
+        db.add_password(Some(&name), &hash).unwrap();
+        assert_eq!(db.password_by_name(&name).unwrap(), hash);
+        assert!(matches!(db.add_password(Some(&name), "other"), Err(DbError::Conflict)));
+        assert!(!db.unnamed_passwords().unwrap().contains(&hash));
+        assert!(matches!(db.password_by_name(&unique("missing")), Err(DbError::NotFound)));
+        Client::connect(&url, NoTls).unwrap().execute("DELETE FROM passwords WHERE name = $1", &[&name]).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a postgres server in SHORTY_TEST_PG_URL"]
+    fn clears_legacy_ip_hashes_after_a_week() {
+        let url = test_url();
+        let db = PostgresDB::init_database(&url).unwrap();
+        let (old, new) = (unique("pgold"), unique("pgnew"));
+        db.store_shortened(&NewLink { ip_hash: "3104834031", ..link(&old, "https://old.org", None) }).unwrap();
+        db.store_shortened(&NewLink { ip_hash: "1234", ..link(&new, "https://new.org", None) }).unwrap();
+        let mut client = Client::connect(&url, NoTls).unwrap();
+        client.execute("UPDATE urls SET created = created - INTERVAL '8 days' WHERE short = $1", &[&old]).unwrap();
+
+        db.clear_legacy_ip_hashes().unwrap();
+        let mut ip_hash = |short: &str| -> String {
+            client.query_one("SELECT ip_hash FROM urls WHERE short = $1", &[&short]).unwrap().get(0)
+        };
+        assert_eq!((ip_hash(&old), ip_hash(&new)), (String::new(), "1234".to_string()));
+        db.delete_shortened(&old).unwrap();
+        db.delete_shortened(&new).unwrap();
+    }
+}
@@ -0,0 +1,595 @@
+use rusqlite::{Connection, Row, Transaction, params, TransactionBehavior, ErrorCode, OptionalExtension};
+use std::path::Path;
+use std::time::Duration;
+use crate::log;
+use super::{Database, DbError, UrlStats, ApiKey, NewLink, Redirect, UrlChange, Visit, ClickStats};
+use crate::auth::Scope;
+use crate::hll::HyperLogLog;
+use rand::Rng;
+
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        match &e {
+            rusqlite::Error::QueryReturnedNoRows => DbError::NotFound,
+            rusqlite::Error::SqliteFailure(err, _) => match err.code {
+                ErrorCode::ConstraintViolation => DbError::Conflict,
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => DbError::Busy,
+                ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => DbError::Corrupt,
+                _ => DbError::Other(e.to_string())
+            },
+            _ => DbError::Other(e.to_string())
+        }
+    }
+}
+
+
+pub struct SQLiteDB {
+    connection: Connection
+}
+
+/// Schema migrations, applied in order at startup. Migration `i` upgrades to schema version `i + 1`.
+/// Only append new migrations, released ones must never change.
+const SQLITE_MIGRATIONS: &[&str] = &[
+    // 1: initial schema, the tables may already exist in databases created by hand
+    "CREATE TABLE IF NOT EXISTS urls (
+        short TEXT NOT NULL UNIQUE,
+        long TEXT NOT NULL,
+        ip_hash INTEGER NOT NULL,
+        created TEXT NOT NULL,
+        redirects INTEGER NOT NULL,
+        last_redirect TEXT NOT NULL,
+        PRIMARY KEY(short)
+    );
+    CREATE TABLE IF NOT EXISTS passwords (
+        password TEXT NOT NULL UNIQUE,
+        PRIMARY KEY(password)
+    );",
+    // 2: runtime settings
+    "CREATE TABLE IF NOT EXISTS settings (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+    // 3: scoped api keys
+    "CREATE TABLE api_keys (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        scopes TEXT NOT NULL,
+        quota INTEGER,
+        created TEXT NOT NULL,
+        last_used TEXT,
+        revoked TEXT
+    );
+    ALTER TABLE urls ADD COLUMN api_key INTEGER REFERENCES api_keys(id);",
+    // 4: link expiration
+    "ALTER TABLE urls ADD COLUMN expires_at TEXT;
+    CREATE INDEX urls_expires_at ON urls(expires_at);
+    CREATE TABLE expired_urls (
+        short TEXT NOT NULL,
+        long TEXT NOT NULL,
+        ip_hash INTEGER NOT NULL,
+        created TEXT NOT NULL,
+        redirects INTEGER NOT NULL,
+        last_redirect TEXT NOT NULL,
+        api_key INTEGER,
+        expires_at TEXT NOT NULL,
+        archived TEXT NOT NULL
+    );",
+    // 5: click limited urls, used up urls are archived without expires_at
+    "ALTER TABLE urls ADD COLUMN max_redirects INTEGER;
+    CREATE TABLE expired_urls_new (
+        short TEXT NOT NULL,
+        long TEXT NOT NULL,
+        ip_hash INTEGER NOT NULL,
+        created TEXT NOT NULL,
+        redirects INTEGER NOT NULL,
+        last_redirect TEXT NOT NULL,
+        api_key INTEGER,
+        expires_at TEXT,
+        max_redirects INTEGER,
+        archived TEXT NOT NULL
+    );
+    INSERT INTO expired_urls_new (short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, archived)
+        SELECT short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, archived FROM expired_urls;
+    DROP TABLE expired_urls;
+    ALTER TABLE expired_urls_new RENAME TO expired_urls;",
+    // 6: passphrase protected urls
+    "ALTER TABLE urls ADD COLUMN passphrase_hash TEXT;",
+    // 7: redirect status per url
+    "ALTER TABLE urls ADD COLUMN redirect_code INTEGER;",
+    // 8: append-only log of target changes
+    "CREATE TABLE url_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        short TEXT NOT NULL,
+        old_long TEXT NOT NULL,
+        new_long TEXT NOT NULL,
+        changed TEXT NOT NULL,
+        actor TEXT NOT NULL
+    );
+    CREATE INDEX url_history_short ON url_history(short);",
+    // 9: click log
+    "CREATE TABLE clicks (
+        short TEXT NOT NULL,
+        clicked TEXT NOT NULL,
+        ip_hash INTEGER NOT NULL,
+        referer TEXT,
+        user_agent TEXT,
+        accept_language TEXT
+    );
+    CREATE INDEX clicks_short_clicked ON clicks(short, clicked);
+    CREATE INDEX clicks_clicked ON clicks(clicked);",
+    // 10: visits of bots are counted separately
+    "ALTER TABLE urls ADD COLUMN bot_redirects INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE expired_urls ADD COLUMN bot_redirects INTEGER NOT NULL DEFAULT 0;",
+    // 11: unique visitor sketches, see `hll`
+    "ALTER TABLE urls ADD COLUMN visitors BLOB;
+    CREATE TABLE daily_visitors (
+        short TEXT NOT NULL,
+        day TEXT NOT NULL,
+        registers BLOB NOT NULL,
+        PRIMARY KEY(short, day)
+    );
+    CREATE TABLE visitor_salts (day TEXT NOT NULL PRIMARY KEY, salt BLOB NOT NULL);",
+    // 12: keyed ip hashes are stored as hex, the old u32 hashes become decimal text
+    "CREATE TABLE urls_new (
+        short TEXT NOT NULL UNIQUE,
+        long TEXT NOT NULL,
+        ip_hash TEXT NOT NULL,
+        created TEXT NOT NULL,
+        redirects INTEGER NOT NULL,
+        last_redirect TEXT NOT NULL,
+        api_key INTEGER REFERENCES api_keys(id),
+        expires_at TEXT,
+        max_redirects INTEGER,
+        passphrase_hash TEXT,
+        redirect_code INTEGER,
+        bot_redirects INTEGER NOT NULL DEFAULT 0,
+        visitors BLOB,
+        PRIMARY KEY(short)
+    );
+    INSERT INTO urls_new (short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, max_redirects,
+            passphrase_hash, redirect_code, bot_redirects, visitors)
+        SELECT short, long, CAST(ip_hash AS TEXT), created, redirects, last_redirect, api_key, expires_at, max_redirects,
+            passphrase_hash, redirect_code, bot_redirects, visitors FROM urls;
+    DROP TABLE urls;
+    ALTER TABLE urls_new RENAME TO urls;
+    CREATE INDEX urls_expires_at ON urls(expires_at);
+    CREATE INDEX urls_ip_hash ON urls(ip_hash, created);
+
+    CREATE TABLE expired_urls_new (
+        short TEXT NOT NULL,
+        long TEXT NOT NULL,
+        ip_hash TEXT NOT NULL,
+        created TEXT NOT NULL,
+        redirects INTEGER NOT NULL,
+        last_redirect TEXT NOT NULL,
+        api_key INTEGER,
+        expires_at TEXT,
+        max_redirects INTEGER,
+        archived TEXT NOT NULL,
+        bot_redirects INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT INTO expired_urls_new (short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at,
+            max_redirects, archived, bot_redirects)
+        SELECT short, long, CAST(ip_hash AS TEXT), created, redirects, last_redirect, api_key, expires_at,
+            max_redirects, archived, bot_redirects FROM expired_urls;
+    DROP TABLE expired_urls;
+    ALTER TABLE expired_urls_new RENAME TO expired_urls;
+
+    CREATE TABLE clicks_new (
+        short TEXT NOT NULL,
+        clicked TEXT NOT NULL,
+        ip_hash TEXT NOT NULL,
+        referer TEXT,
+        user_agent TEXT,
+        accept_language TEXT
+    );
+    INSERT INTO clicks_new (short, clicked, ip_hash, referer, user_agent, accept_language)
+        SELECT short, clicked, CAST(ip_hash AS TEXT), referer, user_agent, accept_language FROM clicks;
+    DROP TABLE clicks;
+    ALTER TABLE clicks_new RENAME TO clicks;
+    CREATE INDEX clicks_short_clicked ON clicks(short, clicked);
+    CREATE INDEX clicks_clicked ON clicks(clicked);",
+    // 13: named passwords, so a login checks one hash instead of all of them. Old rows stay unnamed
+    "ALTER TABLE passwords ADD COLUMN name TEXT;
+    CREATE UNIQUE INDEX passwords_name ON passwords(name);"
+];
+
+/// urls that stopped working, `?1` is the current time. Bots use up `max_redirects` as well
+const EXPIRED_CONDITION: &str = "expires_at <= ?1 OR redirects + bot_redirects >= max_redirects";
+
+/// the unkeyed `u32` ip hashes of old versions, decimal text since migration 12
+const LEGACY_IP_HASH: &str = "ip_hash <> '' AND length(ip_hash) <= 10 AND ip_hash NOT GLOB '*[^0-9]*'";
+
+/// tables with rows of a short url, which are deleted with it
+const LINK_TABLES: [&str; 3] = ["url_history", "clicks", "daily_visitors"];
+
+const API_KEY_COLUMNS: &str = "id, name, scopes, quota, created, last_used, revoked IS NOT NULL";
+
+/// the local time like `datetime('now', 'localtime')`, to use the same time in several statements
+fn now_sql(connection: &Connection) -> rusqlite::Result<String> {
+    connection.query_row("SELECT datetime('now', 'localtime')", params![], |row| row.get(0))
+}
+
+fn api_key_from_row(row: &Row<'_>) -> rusqlite::Result<ApiKey> {
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scopes: Scope::parse_list(&row.get::<_, String>(2)?),
+        quota: row.get(3)?,
+        created: row.get(4)?,
+        last_used: row.get(5)?,
+        revoked: row.get(6)?
+    })
+}
+
+impl SQLiteDB {
+    pub fn init_database<P: AsRef<Path>>(path: P) -> Result<Box<dyn Database>, DbError> {
+        let mut connection = Connection::open(path)?;
+        // other workers may hold the write lock
+        connection.busy_timeout(Duration::from_secs(5))?;
+        Self::migrate(&mut connection)?;
+        Ok(Box::new(SQLiteDB{connection}))
+    }
+
+    /// Creates the schema or upgrades it to the newest version
+    fn migrate(connection: &mut Connection) -> Result<(), DbError> {
+        // only one connection may migrate at a time
+        let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER NOT NULL PRIMARY KEY, applied TEXT NOT NULL)",
+            params![]
+        )?;
+        let version: i64 = tx.query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations", params![], |row| row.get(0))?;
+
+        for (idx, migration) in SQLITE_MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let new_version = idx as i64 + 1;
+            log(format!("Migrating database to schema version {}", new_version));
+            tx.execute_batch(migration)?;
+            tx.execute(
+                "INSERT INTO schema_migrations (version, applied) VALUES (?, datetime('now', 'localtime'))",
+                [new_version]
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+
+impl Database for SQLiteDB {
+    fn store_shortened(&self, link: &NewLink) -> Result<(), DbError> {
+        self.connection.execute(
+            "INSERT INTO urls (short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, max_redirects, \
+            passphrase_hash, redirect_code) \
+            VALUES (?, ?, ?, datetime('now', 'localtime'), 0, datetime('now', 'localtime'), ?, ?, ?, ?, ?)",
+            params![link.short, link.long, link.ip_hash, link.api_key, link.expires_at, link.max_redirects,
+                link.passphrase_hash, link.redirect_code]
+        )?;
+        Ok(())
+    }
+
+    fn urls_stored_last_7_days(&self, ip_hashes: &[String]) -> Result<u32, DbError> {
+        let placeholders = vec!["?"; ip_hashes.len()].join(", ");
+        Ok(self.connection.query_row(
+            &format!("SELECT Count(*) FROM urls WHERE ip_hash IN ({}) AND created > datetime('now', 'localtime', '-7 days')", placeholders),
+            ip_hashes, |row| row.get(0))?)
+    }
+
+    fn urls_stored_by_key_last_7_days(&self, api_key: i64) -> Result<u32, DbError> {
+        Ok(self.connection.query_row(
+            "SELECT Count(*) FROM urls WHERE api_key = ? AND created > datetime('now', 'localtime', '-7 days')", [api_key], |row| row.get(0))?)
+    }
+
+
+    fn forward(&mut self, short_url: &str, visit: &Visit) -> Result<Redirect, DbError> {
+        // take the write lock up front, upgrading a read lock fails if another worker writes
+        let tx = self.connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // get url, nobody else can redirect between this check and the increment
+        let now = now_sql(&tx)?;
+        let (redirect, expired, visitors) = tx.query_row(
+            &format!("SELECT long, redirect_code, COALESCE({}, 0), visitors FROM urls WHERE short = ?2", EXPIRED_CONDITION),
+            params![now, short_url],
+            |row| Ok((Redirect { long: row.get(0)?, redirect_code: row.get(1)? }, row.get(2)?, row.get::<_, Option<Vec<u8>>>(3)?)))?;
+        if expired {
+            return Err(DbError::Expired);
+        }
+
+        if visit.bot {
+            tx.execute("UPDATE urls SET bot_redirects = bot_redirects + 1 WHERE short = ?", &[short_url])?;
+        } else {
+            tx.execute("UPDATE urls SET redirects = redirects + 1, last_redirect = ?1 WHERE short = ?2", params![now, short_url])?;
+        }
+        if let Some(click) = &visit.click {
+            tx.execute(
+                "INSERT INTO clicks (short, clicked, ip_hash, referer, user_agent, accept_language) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![short_url, now, click.ip_hash, click.referer, click.user_agent, click.accept_language]
+            )?;
+        }
+        if let Some(visitor) = visit.visitor {
+            let mut all_time = HyperLogLog::from_bytes(visitors.as_deref().unwrap_or_default());
+            all_time.insert(visitor);
+            tx.execute("UPDATE urls SET visitors = ?1 WHERE short = ?2", params![all_time.as_bytes(), short_url])?;
+
+            let day = &now[..10];
+            let registers: Option<Vec<u8>> = tx.query_row(
+                "SELECT registers FROM daily_visitors WHERE short = ?1 AND day = ?2", &[short_url, day], |row| row.get(0)
+            ).optional()?;
+            let mut daily = HyperLogLog::from_bytes(registers.as_deref().unwrap_or_default());
+            daily.insert(visitor);
+            tx.execute(
+                "INSERT OR REPLACE INTO daily_visitors (short, day, registers) VALUES (?1, ?2, ?3)",
+                params![short_url, day, daily.as_bytes()]
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(redirect)
+    }
+
+    fn url_passphrase(&self, short_url: &str) -> Result<Option<String>, DbError> {
+        let (hash, expired): (Option<String>, bool) = self.connection.query_row(
+            &format!("SELECT passphrase_hash, COALESCE({}, 0) FROM urls WHERE short = ?2", EXPIRED_CONDITION),
+            params![now_sql(&self.connection)?, short_url], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        if expired {
+            return Err(DbError::Expired);
+        }
+        Ok(hash)
+    }
+
+    fn peek_long_url(&self, short_url: &str) -> Result<String, DbError> {
+        Ok(self.connection.query_row(
+            "SELECT long FROM urls WHERE short = ?", &[short_url], |row| row.get(0))?)
+
+    }
+
+    fn url_stats(&self, short_url: &str) -> Result<UrlStats, DbError> {
+        Ok(self.connection.query_row(
+            "SELECT long, created, redirects, last_redirect, expires_at, max_redirects, passphrase_hash IS NOT NULL, \
+            redirect_code, bot_redirects, visitors FROM urls WHERE short = ?", &[short_url],
+            |row| Ok(UrlStats {
+                long: row.get(0)?,
+                created: row.get(1)?,
+                redirects: row.get(2)?,
+                last_redirect: row.get(3)?,
+                expires_at: row.get(4)?,
+                max_redirects: row.get(5)?,
+                protected: row.get(6)?,
+                redirect_code: row.get(7)?,
+                bot_redirects: row.get(8)?,
+                unique_visitors: row.get::<_, Option<Vec<u8>>>(9)?
+                    .map_or(0, |v| HyperLogLog::from_bytes(&v).estimate())
+            }))?)
+    }
+
+    fn update_long_url(&self, short_url: &str, long_url: &str, actor: &str) -> Result<(), DbError> {
+        // `&self` only, but no other statement runs on this connection meanwhile
+        let tx = Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+        let old: String = tx.query_row("SELECT long FROM urls WHERE short = ?", &[short_url], |row| row.get(0))?;
+        tx.execute(
+            "INSERT INTO url_history (short, old_long, new_long, changed, actor) \
+            VALUES (?1, ?2, ?3, datetime('now', 'localtime'), ?4)",
+            params![short_url, old, long_url, actor]
+        )?;
+        tx.execute("UPDATE urls SET long = ? WHERE short = ?", &[long_url, short_url])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn url_history(&self, short_url: &str) -> Result<Vec<UrlChange>, DbError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT id, old_long, new_long, changed, actor FROM url_history WHERE short = ? ORDER BY id DESC")?;
+        let history = stmt.query_map(&[short_url], |row| Ok(UrlChange {
+            id: row.get(0)?,
+            old_long: row.get(1)?,
+            new_long: row.get(2)?,
+            changed: row.get(3)?,
+            actor: row.get(4)?
+        }))?.collect::<Result<_, _>>()?;
+        Ok(history)
+    }
+
+    fn delete_shortened(&self, short_url: &str) -> Result<(), DbError> {
+        // `&self` only, but no other statement runs on this connection meanwhile
+        let tx = Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+        // rolled back when dropped
+        if tx.execute("DELETE FROM urls WHERE short = ?", &[short_url])? == 0 {
+            return Err(DbError::NotFound);
+        }
+        for table in &LINK_TABLES {
+            tx.execute(&format!("DELETE FROM {} WHERE short = ?", table), &[short_url])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn archive_expired(&self) -> Result<u32, DbError> {
+        // `&self` only, but no other statement runs on this connection meanwhile
+        let tx = Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+        let now = now_sql(&tx)?;
+        tx.execute(&format!(
+            "INSERT INTO expired_urls \
+            (short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, max_redirects, bot_redirects, archived) \
+            SELECT short, long, ip_hash, created, redirects, last_redirect, api_key, expires_at, max_redirects, bot_redirects, ?1 \
+            FROM urls WHERE {}", EXPIRED_CONDITION), &[&now])?;
+        for table in &LINK_TABLES {
+            tx.execute(&format!("DELETE FROM {} WHERE short IN (SELECT short FROM urls WHERE {})", table, EXPIRED_CONDITION),
+                       &[&now])?;
+        }
+        let archived = tx.execute(&format!("DELETE FROM urls WHERE {}", EXPIRED_CONDITION), &[&now])?;
+        tx.commit()?;
+        Ok(archived as u32)
+    }
+
+    fn clear_legacy_ip_hashes(&self) -> Result<u32, DbError> {
+        let mut cleared = 0;
+        for table in &["urls", "expired_urls"] {
+            cleared += self.connection.execute(&format!(
+                "UPDATE {} SET ip_hash = '' WHERE {} AND created <= datetime('now', 'localtime', '-7 days')",
+                table, LEGACY_IP_HASH), params![])?;
+        }
+        Ok(cleared as u32)
+    }
+
+    fn click_stats(&self, short_url: &str, since: &str) -> Result<ClickStats, DbError> {
+        let counts = |sql: &str| -> Result<Vec<(String, u32)>, DbError> {
+            let mut stmt = self.connection.prepare(sql)?;
+            let counts = stmt.query_map(&[short_url, since], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+            Ok(counts)
+        };
+        Ok(ClickStats {
+            daily: counts("SELECT substr(clicked, 1, 10) AS day, COUNT(*) FROM clicks \
+                WHERE short = ?1 AND clicked >= ?2 GROUP BY day ORDER BY day")?,
+            hourly: counts("SELECT substr(clicked, 1, 13) || ':00' AS hour, COUNT(*) FROM clicks \
+                WHERE short = ?1 AND clicked >= ?2 GROUP BY hour ORDER BY hour")?,
+            top_referrers: counts("SELECT referer, COUNT(*) AS n FROM clicks \
+                WHERE short = ?1 AND clicked >= ?2 AND referer IS NOT NULL GROUP BY referer ORDER BY n DESC, referer LIMIT 10")?
+        })
+    }
+
+    fn purge_clicks(&self, since: &str) -> Result<u32, DbError> {
+        Ok(self.connection.execute("DELETE FROM clicks WHERE clicked < ?", &[since])? as u32)
+    }
+
+    fn daily_unique_visitors(&self, short_url: &str, since_day: &str) -> Result<Vec<(String, u32)>, DbError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT day, registers FROM daily_visitors WHERE short = ?1 AND day >= ?2 ORDER BY day")?;
+        let visitors = stmt.query_map(&[short_url, since_day], |row| {
+            Ok((row.get(0)?, HyperLogLog::from_bytes(&row.get::<_, Vec<u8>>(1)?).estimate()))
+        })?.collect::<Result<_, _>>()?;
+        Ok(visitors)
+    }
+
+    fn purge_daily_visitors(&self, day: &str) -> Result<u32, DbError> {
+        Ok(self.connection.execute("DELETE FROM daily_visitors WHERE day < ?", &[day])? as u32)
+    }
+
+    fn visitor_salt(&self, day: &str) -> Result<Vec<u8>, DbError> {
+        // another worker may have created it meanwhile, then its salt is kept
+        let salt: [u8; 32] = rand::thread_rng().gen();
+        self.connection.execute("INSERT OR IGNORE INTO visitor_salts (day, salt) VALUES (?1, ?2)", params![day, &salt[..]])?;
+        self.connection.execute("DELETE FROM visitor_salts WHERE day < ?", &[day])?;
+        Ok(self.connection.query_row("SELECT salt FROM visitor_salts WHERE day = ?", &[day], |row| row.get(0))?)
+    }
+
+    fn password_by_name(&self, name: &str) -> Result<String, DbError> {
+        Ok(self.connection.query_row("SELECT password FROM passwords WHERE name = ?", &[name], |row| row.get(0))?)
+    }
+
+    fn unnamed_passwords(&self) -> Result<Vec<String>, DbError> {
+        let mut stmt = self.connection.prepare("SELECT password FROM passwords WHERE name IS NULL")?;
+        let passwords = stmt.query_map(params![], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        Ok(passwords)
+    }
+
+    fn add_password(&self, name: Option<&str>, hash: &str) -> Result<(), DbError> {
+        self.connection.execute("INSERT INTO passwords (name, password) VALUES (?, ?)", params![name, hash])?;
+        Ok(())
+    }
+
+    fn replace_password(&self, old: &str, new: &str) -> Result<(), DbError> {
+        match self.connection.execute("UPDATE passwords SET password = ? WHERE password = ?", &[new, old])? {
+            0 => Err(DbError::NotFound),
+            _ => Ok(())
+        }
+    }
+
+    fn store_api_key(&self, name: &str, key_hash: &str, scopes: &[Scope], quota: Option<u32>) -> Result<i64, DbError> {
+        self.connection.execute(
+            "INSERT INTO api_keys (name, key_hash, scopes, quota, created) VALUES (?, ?, ?, ?, datetime('now', 'localtime'))",
+            params![name, key_hash, Scope::join(scopes), quota]
+        )?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    fn api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, DbError> {
+        Ok(self.connection.query_row(
+            &format!("SELECT {} FROM api_keys WHERE key_hash = ?", API_KEY_COLUMNS), &[key_hash], api_key_from_row)?)
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, DbError> {
+        let mut stmt = self.connection.prepare(&format!("SELECT {} FROM api_keys ORDER BY id", API_KEY_COLUMNS))?;
+        let keys = stmt.query_map(params![], api_key_from_row)?.collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    fn touch_api_key(&self, id: i64) -> Result<(), DbError> {
+        self.connection.execute("UPDATE api_keys SET last_used = datetime('now', 'localtime') WHERE id = ?", [id])?;
+        Ok(())
+    }
+
+    fn revoke_api_key(&self, id: i64) -> Result<(), DbError> {
+        match self.connection.execute(
+            "UPDATE api_keys SET revoked = COALESCE(revoked, datetime('now', 'localtime')) WHERE id = ?", [id])? {
+            0 => Err(DbError::NotFound),
+            _ => Ok(())
+        }
+    }
+
+    fn get_setting(&self, key: &str) -> Result<Option<String>, DbError> {
+        match self.connection.query_row(
+            "SELECT value FROM settings WHERE key = ?", &[key], |row| row.get(0)) {
+            Ok(v) => Ok(Some(v)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), DbError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)", &[key, value]
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bots_use_up_one_time_links() {
+        let mut db = SQLiteDB::init_database(":memory:").unwrap();
+        db.store_shortened(&NewLink {
+            short: "once",
+            long: "https://once.org",
+            ip_hash: "test",
+            api_key: None,
+            expires_at: None,
+            max_redirects: Some(1),
+            passphrase_hash: None,
+            redirect_code: None
+        }).unwrap();
+        let bot = Visit { bot: true, click: None, visitor: None };
+        db.forward("once", &bot).unwrap();
+        assert!(matches!(db.forward("once", &bot), Err(DbError::Expired)));
+        assert!(matches!(db.forward("once", &Visit { bot: false, click: None, visitor: None }), Err(DbError::Expired)));
+        assert_eq!(db.archive_expired().unwrap(), 1);
+    }
+
+    #[test]
+    fn clears_legacy_ip_hashes_after_a_week() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        SQLiteDB::migrate(&mut connection).unwrap();
+        let db = SQLiteDB { connection };
+        for (short, ip_hash) in [("old", "3104834031"), ("new", "1234"), ("keyed", "0123456789abcdef0123456789abcdef")] {
+            db.store_shortened(&NewLink {
+                short,
+                long: "https://example.org",
+                ip_hash,
+                api_key: None,
+                expires_at: None,
+                max_redirects: None,
+                passphrase_hash: None,
+                redirect_code: None
+            }).unwrap();
+        }
+        db.connection.execute("UPDATE urls SET created = datetime('now', 'localtime', '-8 days') WHERE short <> 'new'",
+                              params![]).unwrap();
+        assert_eq!(db.clear_legacy_ip_hashes().unwrap(), 1);
+        let hashes: Vec<(String, String)> = db.connection.prepare("SELECT short, ip_hash FROM urls ORDER BY short").unwrap()
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(hashes, [("keyed".to_string(), "0123456789abcdef0123456789abcdef".to_string()),
+                            ("new".to_string(), "1234".to_string()), ("old".to_string(), String::new())]);
+    }
+}
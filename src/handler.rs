@@ -1,26 +1,35 @@
-use std::net::TcpStream;
-use crate::response::{Response, ResponseCode, ResponseBody};
+use crate::response::{Response, ResponseCode, ResponseBody, json_string, html_escape};
 use crate::request::{Request, RE_SHORT_URL_VALIDATE, RE_LONG_URL_VALIDATE, RequestBody, Method};
-use crate::database::Database;
+use crate::database::{Database, DbError, ApiKey, NewLink, Redirect, Visit, Click};
+use crate::auth::{self, Scope, Principal, AuthError};
+use crate::ratelimit::{self, Action};
 use rand::Rng;
 use crate::log;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use crate::api;
+use crate::bots;
+use crate::config::{config, Settings};
+use std::net::{IpAddr, Ipv6Addr};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use sha2::{Sha256, Digest};
+use hmac::{Hmac, Mac};
+use chrono::{Local, NaiveDate, NaiveDateTime};
 
 /// the urls that are forbidden to use
-pub const RESERVED_URLS: [&str; 5] = [
+pub const RESERVED_URLS: [&str; 6] = [
     "create",
     "free",
     "delete",
     "status",
-    "config"
+    "config",
+    "api"
 ];
 
 
 /// Contains all "static" handlers with fixed route (could also contain shortys)
 /// They are tested top-to-bottom
 /// if the test method is true, the handler is executed
-pub const HANDLERS: [(&'static str, RoutingFn, HandlerFn); 4] = [
+pub const HANDLERS: [(&'static str, RoutingFn, HandlerFn); 8] = [
     // home page
     ("home_page", |req| req.url.len() == 0 && req.method == Method::Get
      , home_page),
@@ -30,6 +39,18 @@ pub const HANDLERS: [(&'static str, RoutingFn, HandlerFn); 4] = [
     //
     ("free_check", |req| req.url.len() == 1 && req.url[0].eq_ignore_ascii_case("free"),
      free_check),
+    // delete api
+    ("delete_page", |req| req.url.len() == 1 && req.url[0].eq_ignore_ascii_case("delete") && req.method == Method::Post,
+     delete_page),
+    // link statistics
+    ("status_page", |req| req.url.len() == 2 && req.url[0].eq_ignore_ascii_case("status") && req.method == Method::Get,
+     status_page),
+    // admin settings
+    ("config_page", |req| req.url.len() == 1 && req.url[0].eq_ignore_ascii_case("config") && req.method == Method::Post,
+     config_page),
+    // json api
+    ("api_v1", |req| req.url.len() >= 3 && req.url[0].eq_ignore_ascii_case("api") && req.url[1].eq_ignore_ascii_case("v1"),
+     api::handle_v1),
     // static
     ("static", |req| req.url.len() > 1 && req.url[0].eq_ignore_ascii_case("static"), static_content)
 ];
@@ -39,68 +60,378 @@ type RoutingFn = fn(req: &Request) -> bool;
 pub enum HandlerError {
     E404,
     E400(String),
-    #[allow(dead_code)]
+    Db(DbError),
     Custom(Response)
 }
 
+impl From<DbError> for HandlerError {
+    fn from(e: DbError) -> Self {
+        HandlerError::Db(e)
+    }
+}
+
+impl From<AuthError> for HandlerError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::TooManyFailures(retry_after) => HandlerError::Custom(too_many_requests(retry_after)),
+            AuthError::Db(e) => HandlerError::Db(e)
+        }
+    }
+}
+
 type HandlerFn = fn(req: &Request, db: &dyn Database) -> Result<Response, HandlerError>;
 
 
 /// the post endpoint for a page-create-request
 fn create_page(req: &Request, db: &dyn Database) -> Result<Response, HandlerError> {
-    log(format!("create {:?}", req.body));
-    match &req.body {
-        Some(RequestBody::FormUrlEncoded(map)) => {
-            if let (Some(code), Some(long)) = (map.get("password"), map.get("long-url")) {
-
-                let mut long = long.clone();
-                if !long.starts_with("https://") && !long.starts_with("http://") {
-                    long.insert_str(0, "https://");
+    if let Some(RequestBody::FormUrlEncoded(map)) = &req.body {
+        if let Some(long) = map.get("long-url") {
+            let principal = match auth::authenticate_form(req, db)? {
+                Some(p) => p,
+                None => return Err(HandlerError::E400("Wrong password".into()))
+            };
+            if !principal.allows(Scope::Create) {
+                return Err(HandlerError::E400("This key may not create urls".into()));
+            }
+            rate_limit(Action::Create, &rate_limit_client(req, Some(&principal))).map_err(HandlerError::Custom)?;
+            let options = LinkOptions {
+                expires_at: match map.get("expires-at").filter(|e| !e.is_empty()) {
+                    Some(e) => Some(parse_expires_at(e).map_err(HandlerError::E400)?),
+                    None => None
+                },
+                max_redirects: match map.get("max-redirects").filter(|m| !m.is_empty()) {
+                    Some(m) => Some(parse_max_redirects(m).map_err(HandlerError::E400)?),
+                    None => None
+                },
+                passphrase: map.get("visitor-passphrase").filter(|p| !p.is_empty()).cloned(),
+                redirect_code: match map.get("redirect-code").filter(|c| !c.is_empty()) {
+                    Some(c) => Some(parse_redirect_code(c).map_err(HandlerError::E400)?),
+                    None => None
                 }
+            };
+            // TODO do we need transactions?
+            if let Some(long) = normalize_long_url(long) {
+                // authorized and long url valid
+                let short = create_short_url(db, &long, map.get("short-url").map(|s| s.as_str()), &options,
+                                             &req.ip, principal.api_key())?;
+
+                let mut file = std::fs::read_to_string("./page/dist/created.html").unwrap();
+                file = file.replace("{{short-url}}", &html_escape(&format!("{}/{}", config().base_url, short)));
+                file = file.replace("{{long-url}}", &html_escape(&long));
+                return Ok(Response{
+                    code: ResponseCode::Ok,
+                    custom_headers: None,
+                    body: ResponseBody::Html(file)
+                })
+            }
+        }
+    }
+    Err(HandlerError::E400("No body transmitted".into()))
+}
 
-                // TODO do we need transactions?
-                if db.is_password(code) && validate_long_url(&long).is_ok() {
-                    // pw correct and long url valid
-                    let short = map.get("short-url").map_or_else(|| gen_free_random_url(db),|s| s.to_owned());
-                    if db.peek_long_url(&short).is_ok() {
-                        return Err(HandlerError::E400(format!("Short URL {} allready exists.", &short)));
-                    }
-                    let ip_hash = {
-                        let mut h = DefaultHasher::new();
-                        req.ip.hash(&mut h);
-                        h.finish() as u32
-                    };
-
-                    // check if user contingent is maxed out
-                    let urls_created = db.urls_stored_last_7_days(ip_hash);
-                    log(format!("Urls created by ith ip: {}", urls_created));
-
-                    if urls_created > 100 {
-                        return Err(HandlerError::E400("You allready created 100 URLs in the last 7 days... Thats a lot!".into()));
-                    }
-
-                    log(format!("Storing {} -> {}", &short, long));
-                    db.store_shortened(&long, &short, ip_hash).map_err(|e| HandlerError::E400(e))?;
-
-                    let mut file = std::fs::read_to_string("./page/dist/created.html").unwrap();
-                    let host_url = std::env::var("SHORTY_BASE_URL").unwrap();
-                    file = file.replace("{{short-url}}", &format!("{}/{}", host_url, short));
-                    file = file.replace("{{long-url}}", &long);
-                    return Ok(Response{
-                        code: ResponseCode::Ok,
-                        custom_headers: None,
-                        body: ResponseBody::Html(file)
-                    })
-                }
+/// Why `create_short_url` failed
+pub enum CreateError {
+    Taken(String),
+    /// reserved or invalid chars
+    NotAllowed(String),
+    /// contains the number of urls created by this ip / api key in the last 7 days
+    QuotaExceeded(u32),
+    Db(DbError)
+}
+
+impl From<DbError> for CreateError {
+    fn from(e: DbError) -> Self {
+        CreateError::Db(e)
+    }
+}
+
+impl From<CreateError> for HandlerError {
+    fn from(e: CreateError) -> Self {
+        match e {
+            CreateError::Taken(short) => HandlerError::E400(format!("Short URL {} allready exists.", short)),
+            CreateError::NotAllowed(short) => HandlerError::E400(format!("Short URL {} is not allowed.", short)),
+            CreateError::QuotaExceeded(n) =>
+                HandlerError::E400(format!("You allready created {} URLs in the last 7 days... Thats a lot!", n)),
+            CreateError::Db(e) => HandlerError::Db(e)
+        }
+    }
+}
+
+/// adds `https://` if the url has no scheme
+fn with_scheme(long: &str) -> String {
+    let mut long = long.to_owned();
+    if !long.starts_with("https://") && !long.starts_with("http://") {
+        long.insert_str(0, "https://");
+    }
+    long
+}
+
+/// adds `https://` if the url has no scheme, `None` if it is no valid url
+pub fn normalize_long_url(long: &str) -> Option<String> {
+    let long = with_scheme(long);
+    if validate_long_url(&long).is_ok() { Some(long) } else { None }
+}
+
+/// Optional settings of a new short url, from the form or the api
+#[derive(Default)]
+pub struct LinkOptions {
+    pub expires_at: Option<NaiveDateTime>,
+    /// 1 for one-time urls
+    pub max_redirects: Option<u32>,
+    /// visitors have to enter it before they are redirected
+    pub passphrase: Option<String>,
+    /// `None` uses the `default-redirect-code` setting
+    pub redirect_code: Option<u16>
+}
+
+/// Parses the local time a link expires at, which must be in the future.
+/// Accepts `2020-12-24 18:00(:00)`, the `T` separated form of `<input type="datetime-local">`
+/// and a plain date, which expires at the end of that day
+pub fn parse_expires_at(s: &str) -> Result<NaiveDateTime, String> {
+    const FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"];
+    let s = s.trim();
+    let expires_at = FORMATS.iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(23, 59, 59)))
+        .ok_or_else(|| format!("Expiration date {} is no valid date", s))?;
+    if expires_at <= Local::now().naive_local() {
+        return Err(format!("Expiration date {} is in the past", s));
+    }
+    Ok(expires_at)
+}
+
+/// how often a url may be used, at least once and at most `i32::MAX` times (the postgres `INTEGER` column)
+pub fn parse_max_redirects(s: &str) -> Result<u32, String> {
+    match s.trim().parse::<u32>() {
+        Ok(m) if m > 0 && m <= i32::MAX as u32 => Ok(m),
+        _ => Err(format!("Max redirects {} is no number from 1 to {}", s, i32::MAX))
+    }
+}
+
+/// 301, 302, 303, 307 or 308
+pub fn parse_redirect_code(s: &str) -> Result<u16, String> {
+    s.trim().parse().ok()
+        .filter(|c| ResponseCode::redirect(*c).is_some())
+        .ok_or_else(|| format!("Redirect code {} must be 301, 302, 303, 307 or 308", s))
+}
+
+/// the ip as stored in `ip_hash`: the first 128 bits of its HMAC-SHA256 with `SHORTY_IP_HASH_SECRET` as hex
+pub fn hash_ip(ip: &IpAddr) -> String {
+    hmac_ip(ip, &config().ip_hash_secret)
+}
+
+fn hmac_ip(ip: &IpAddr, secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(ip.to_string().as_bytes());
+    mac.finalize().into_bytes()[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Every `ip_hash` the urls of this ip may be stored with: the current one and the one of the previous secret
+pub fn ip_hashes(ip: &IpAddr) -> Vec<String> {
+    let mut hashes = vec![hash_ip(ip)];
+    if let Some(previous) = &config().ip_hash_previous_secret {
+        hashes.push(hmac_ip(ip, previous));
+    }
+    hashes
+}
+
+/// whose bucket a request uses: the api key if there is one, else the ip
+pub fn rate_limit_client(req: &Request, principal: Option<&Principal>) -> String {
+    match principal.and_then(|p| p.api_key()) {
+        Some(key) => format!("key:{}", key.id),
+        None => rate_limit_ip(req.ip)
+    }
+}
+
+/// ipv6 clients by their /64, a single host usually gets all of it
+fn rate_limit_ip(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => format!("ip:{}/64", Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+        ip => format!("ip:{}", ip)
+    }
+}
+
+/// `429` with `Retry-After` if `client` used up its requests for `action` (see `ratelimit::check`)
+pub fn rate_limit(action: Action, client: &str) -> Result<(), Response> {
+    ratelimit::check(action, client).map_err(too_many_requests)
+}
+
+fn too_many_requests(retry_after: u64) -> Response {
+    let mut r = Response::text(ResponseCode::TooManyRequests, "Too many requests, try again later");
+    r.set_header("Retry-After", &retry_after.to_string());
+    r
+}
+
+/// longer header values are cut before they are logged in `clicks`
+const MAX_CLICK_HEADER_LEN: usize = 512;
+
+/// the click to log for a redirect, `None` if this one isn't sampled (see `SHORTY_CLICK_SAMPLE_RATE`)
+fn sampled_click(req: &Request) -> Option<Click<'_>> {
+    let rate = config().click_sample_rate;
+    if rate < 1.0 && rand::thread_rng().gen::<f64>() >= rate {
+        return None;
+    }
+    let header = |name| req.header(name).map(|v| {
+        let mut end = v.len().min(MAX_CLICK_HEADER_LEN);
+        while !v.is_char_boundary(end) {
+            end -= 1;
+        }
+        &v[..end]
+    });
+    Some(Click {
+        ip_hash: hash_ip(&req.ip),
+        referer: header("Referer"),
+        user_agent: header("User-Agent"),
+        accept_language: header("Accept-Language")
+    })
+}
+
+/// Validates and stores a new short url for the (normalized) `long` url, shared by the form and the api.
+/// Generates a random short url if none is given. Returns the short url.
+/// Urls created with an api key count against the key's quota, otherwise against the ip
+pub fn create_short_url(db: &dyn Database, long: &str, short: Option<&str>, options: &LinkOptions, ip: &IpAddr,
+                        key: Option<&ApiKey>) -> Result<String, CreateError> {
+    let settings = Settings::cached(db)?;
+    let short = short.map_or_else(|| gen_free_random_url(db, &settings),|s| s.to_owned());
+    match db.peek_long_url(&short) {
+        Ok(_) => return Err(CreateError::Taken(short)),
+        Err(DbError::NotFound) => {},
+        Err(e) => return Err(e.into())
+    }
+    if !validate_short_url(&short, db, &settings) {
+        return Err(CreateError::NotAllowed(short));
+    }
+
+    // check if user contingent is maxed out
+    let (urls_created, quota) = match key {
+        Some(key) => (db.urls_stored_by_key_last_7_days(key.id)?, key.quota.unwrap_or(settings.max_urls_per_week)),
+        None => (db.urls_stored_last_7_days(&ip_hashes(ip))?, settings.max_urls_per_week)
+    };
+    log(format!("Urls created by ith ip / key: {}", urls_created));
+
+    if urls_created >= quota {
+        return Err(CreateError::QuotaExceeded(urls_created));
+    }
+
+    log(format!("Storing {} -> {}", &short, long));
+    db.store_shortened(&NewLink {
+        short: &short,
+        long,
+        ip_hash: &hash_ip(ip),
+        api_key: key.map(|k| k.id),
+        expires_at: options.expires_at.map(|e| e.format("%Y-%m-%d %H:%M:%S").to_string()),
+        max_redirects: options.max_redirects,
+        passphrase_hash: options.passphrase.as_deref().map(auth::hash_password),
+        redirect_code: options.redirect_code
+    })?;
+    Ok(short)
+}
+
+/// the post endpoint to delete a short url
+/// expects the form field `short-url` and the `password` field or a bearer token
+fn delete_page(req: &Request, db: &dyn Database) -> Result<Response, HandlerError> {
+    if let Some(RequestBody::FormUrlEncoded(map)) = &req.body {
+        if let Some(short) = map.get("short-url") {
+            match auth::authenticate_form(req, db)? {
+                Some(p) if p.allows(Scope::Delete) => {},
+                Some(_) => return Err(HandlerError::Custom(
+                    Response::text(ResponseCode::Forbidden, "This key may not delete urls"))),
+                None => return Err(HandlerError::Custom(Response::text(ResponseCode::Forbidden, "Wrong password")))
             }
-        },
-        None => {}
+
+            log(format!("Deleting {}", short));
+            return match db.delete_shortened(short) {
+                Ok(()) => Ok(Response::text(ResponseCode::Ok, &format!("Deleted {}", short))),
+                Err(DbError::NotFound) => Err(HandlerError::Custom(
+                    Response::text(ResponseCode::NotFound, &format!("Short URL {} does not exist", short)))),
+                Err(e) => Err(e.into())
+            }
+        }
     }
     Err(HandlerError::E400("No body transmitted".into()))
 }
 
+/// statistics of a short url: `/status/<short>`, public on purpose (clicks and history need `Scope::ReadStats`)
+/// answers with json if requested by `?format=json` or the `Accept` header, otherwise with html
+fn status_page(req: &Request, db: &dyn Database) -> Result<Response, HandlerError> {
+    let short = &req.url[1];
+    let stats = db.url_stats(short)?;
+
+    let wants_json = req.params.get("format").is_some_and(|f| f.eq_ignore_ascii_case("json")) ||
+        req.header("Accept").is_some_and(|a| a.contains("application/json"));
+
+    // the target of protected urls is only shown to visitors with the passphrase
+    let long = if stats.protected { None } else { Some(&stats.long) };
+    let body = if wants_json {
+        let last_redirect = if stats.redirects > 0 { json_string(&stats.last_redirect) } else { "null".into() };
+        ResponseBody::Json(format!(
+            "{{\"short\":{},\"long\":{},\"protected\":{},\"created\":{},\"redirects\":{},\"bot_redirects\":{},\"unique_visitors\":{},\"last_redirect\":{},\"expires_at\":{},\"max_redirects\":{},\"redirect_code\":{}}}",
+            json_string(short), long.map_or("null".into(), |l| json_string(l)), stats.protected, json_string(&stats.created),
+            stats.redirects, stats.bot_redirects, stats.unique_visitors, last_redirect, stats.expires_at.as_deref().map_or("null".into(), json_string),
+            stats.max_redirects.map_or("null".into(), |m| m.to_string()),
+            stats.redirect_code.map_or("null".into(), |c| c.to_string())))
+    } else {
+        let mut file = std::fs::read_to_string("./page/dist/status.html").unwrap();
+        file = file.replace("{{short-url}}", &html_escape(short));
+        file = file.replace("{{long-url}}", &html_escape(long.map_or("(protected)", |l| l.as_str())));
+        file = file.replace("{{created}}", &html_escape(&stats.created));
+        file = file.replace("{{redirects}}", &match stats.max_redirects {
+            Some(max) => format!("{} of {}", stats.redirects + stats.bot_redirects, max),
+            None => stats.redirects.to_string()
+        });
+        file = file.replace("{{bot-redirects}}", &stats.bot_redirects.to_string());
+        file = file.replace("{{unique-visitors}}", &stats.unique_visitors.to_string());
+        file = file.replace("{{last-redirect}}",
+                            &html_escape(if stats.redirects > 0 { &stats.last_redirect } else { "never" }));
+        file = file.replace("{{expires-at}}", &html_escape(stats.expires_at.as_deref().unwrap_or("never")));
+        ResponseBody::Html(file)
+    };
+
+    Ok(Response {
+        code: ResponseCode::Ok,
+        custom_headers: None,
+        body
+    })
+}
+
+/// the post endpoint for admin settings
+/// expects the form field `password` (or a bearer token with the admin scope),
+/// every other field is stored as a setting (see `config::SETTING_KEYS`).
+/// Answers with the effective configuration as json
+fn config_page(req: &Request, db: &dyn Database) -> Result<Response, HandlerError> {
+    let map = match &req.body {
+        Some(RequestBody::FormUrlEncoded(map)) => map,
+        _ => return Err(HandlerError::E400("No body transmitted".into()))
+    };
+    let authorized = auth::authenticate_form(req, db)?.is_some_and(|p| p.allows(Scope::Admin));
+    if !authorized {
+        return Err(HandlerError::Custom(Response::text(ResponseCode::Forbidden, "Wrong password")));
+    }
+
+    let mut settings = Settings::load(db)?;
+    let changes: Vec<_> = map.iter().filter(|(k, _)| k.as_str() != "password").collect();
+    // one invalid value changes nothing
+    for (key, value) in &changes {
+        settings.apply(key, value).map_err(HandlerError::E400)?;
+    }
+    for (key, value) in changes {
+        log(format!("Changing setting {} to {}", key, value));
+        db.set_setting(key, value)?;
+    }
+    Settings::invalidate();
+
+    let conf = config();
+    Ok(Response {
+        code: ResponseCode::Ok,
+        custom_headers: None,
+        body: ResponseBody::Json(format!(
+            "{{\"base-url\":{},\"database\":{},\"port\":{},\"debug\":{},\"settings\":{}}}",
+            json_string(&conf.base_url), json_string(&conf.database_display()), conf.port, conf.debug, settings.to_json()))
+    })
+}
+
 /// generates a free (valid) short url
-fn gen_free_random_url(db: &dyn Database) -> String {
+fn gen_free_random_url(db: &dyn Database, settings: &Settings) -> String {
     let mut rg = rand::thread_rng();
     let mut allowed_chars = (b'a'..b'z')
         .chain(b'A'..b'Z')
@@ -109,10 +440,10 @@ fn gen_free_random_url(db: &dyn Database) -> String {
     const ALLOWED_CHARS_LEN: usize = 26 * 2 + 10;
 
     for _ in 0..100 {
-        let res: String = (0..5).map(|_|
+        let res: String = (0..settings.short_url_length).map(|_|
             allowed_chars.nth(rg.gen_range(0, ALLOWED_CHARS_LEN)).unwrap() as char
         ).collect();
-        if validate_short_url(&res, db) {
+        if validate_short_url(&res, db, settings) {
             return res;
         }
     }
@@ -133,55 +464,197 @@ pub fn home_page(_req: &Request, _db: &dyn Database) -> Result<Response, Handler
 
 
 /// generic error page (dynamic)
-pub fn send_gen_error_page(s: &mut TcpStream, error: &str) -> std::io::Result<()> {
+pub fn gen_error_page(error: &str) -> Response {
     let mut page = std::fs::read_to_string("./page/dist/400.html").unwrap();
 
-    page = page.replace("{{error}}", error);
+    page = page.replace("{{error}}", &html_escape(error));
 
-    let r = Response{
+    Response{
         code: ResponseCode::BadRequest,
         custom_headers: None,
         body: ResponseBody::Html(page)
-    };
-    r.write_html11(s)
+    }
+}
+
+/// response for failed database operations
+pub fn db_error_page(e: DbError, req: &Request) -> Response {
+    match e {
+        DbError::NotFound => not_found_page(req),
+        DbError::Expired => gone_page(req),
+        DbError::Conflict => Response::text(ResponseCode::Conflict, "Already exists"),
+        DbError::Busy => {
+            let mut r = Response::text(ResponseCode::ServiceUnavailable, "Database is busy, try again");
+            r.set_header("Retry-After", "1");
+            r
+        },
+        DbError::Corrupt | DbError::Other(_) => {
+            log(format!("Database failed: {}", e));
+            Response::text(ResponseCode::InternalServerError, "Database failed")
+        }
+    }
 }
 
 /// 404 page (dynamic)
-pub fn send_404_page(s: &mut TcpStream, req: &Request) -> std::io::Result<()> {
-    if let Some(accpt) = req.headers.get("Accept") {
+pub fn not_found_page(req: &Request) -> Response {
+    if let Some(accpt) = req.header("Accept") {
         if accpt.contains("text/html") {
             let mut page = std::fs::read_to_string("./page/dist/404.html").unwrap();
 
-            page = page.replace("{{url}}", &req.url.join("/"));
+            page = page.replace("{{url}}", &html_escape(&req.url.join("/")));
 
-            let r = Response{
+            return Response{
                 code: ResponseCode::NotFound,
                 custom_headers: None,
                 body: ResponseBody::Html(page)
             };
-            return r.write_html11(s);
         }
     }
 
-    let r = Response{
+    Response{
         code: ResponseCode::NotFound,
         custom_headers: None,
         body: ResponseBody::Empty
+    }
+}
+
+/// 410 page for expired short urls (dynamic)
+pub fn gone_page(req: &Request) -> Response {
+    let mut page = std::fs::read_to_string("./page/dist/410.html").unwrap();
+
+    page = page.replace("{{url}}", &html_escape(&req.url.join("/")));
+
+    Response{
+        code: ResponseCode::Gone,
+        custom_headers: None,
+        body: ResponseBody::Html(page)
+    }
+}
+
+/// Counts the visit of the short url `req.url[0]` and redirects to its long url.
+/// Bots are counted separately and their clicks are not logged
+pub fn forward_page(req: &Request, db: &mut dyn Database) -> Result<Response, DbError> {
+    let settings = Settings::cached(db)?;
+    let bot = bots::is_bot(req.header("User-Agent").map(|ua| ua.as_str()), &settings.bot_patterns);
+    let visit = if bot {
+        Visit { bot, click: None, visitor: None }
+    } else {
+        Visit { bot, click: sampled_click(req), visitor: Some(visitor_hash(req, db)?) }
+    };
+    let redirect = db.forward(&req.url[0], &visit)?;
+    Ok(redirect_response(req, redirect, &settings))
+}
+
+/// salt of the current day, shared by all workers of this process
+static VISITOR_SALT: Mutex<Option<(String, Vec<u8>)>> = Mutex::new(None);
+
+/// Identifies a visitor by ip and user agent for one day, without storing either (see `Database::visitor_salt`)
+fn visitor_hash(req: &Request, db: &dyn Database) -> Result<u64, DbError> {
+    let day = Local::now().format("%Y-%m-%d").to_string();
+    let salt = {
+        let mut cached = VISITOR_SALT.lock().unwrap_or_else(|e| e.into_inner());
+        match &*cached {
+            Some((d, salt)) if *d == day => salt.clone(),
+            _ => {
+                let salt = db.visitor_salt(&day)?;
+                *cached = Some((day, salt.clone()));
+                salt
+            }
+        }
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&salt);
+    hasher.update(req.ip.to_string().as_bytes());
+    hasher.update([0]);
+    hasher.update(req.header("User-Agent").map_or("", |ua| ua.as_str()).as_bytes());
+    let digest = hasher.finalize();
+    let mut first = [0; 8];
+    first.copy_from_slice(&digest[..8]);
+    Ok(u64::from_be_bytes(first))
+}
+
+/// the redirect to the long url, with the status code of the url or the default one
+fn redirect_response(req: &Request, redirect: Redirect, settings: &Settings) -> Response {
+    let code = if req.method == Method::Post {
+        // the passphrase form is posted, its answer must not be cached or repeated as post
+        ResponseCode::SeeOther
+    } else {
+        let code = redirect.redirect_code.unwrap_or(settings.default_redirect_code);
+        ResponseCode::redirect(code).unwrap_or(ResponseCode::MovedPermanently)
+    };
+
+    let mut h = HashMap::new();
+    h.insert("Location".into(), redirect.long);
+
+    // force browser to use no-cache to allow counting of redirects
+    h.insert("Cache-Control".into(), "no-cache".into());
+    Response {
+        code,
+        custom_headers: Some(h),
+        body: ResponseBody::Empty
+    }
+}
+
+/// Asks for the passphrase of protected urls, on `GET` and after a wrong passphrase.
+/// `None` if the url is public or the posted passphrase is correct, so the request may be forwarded
+pub fn passphrase_gate(req: &Request, db: &dyn Database) -> Result<Option<Response>, DbError> {
+    let short = &req.url[0];
+    let hash = match db.url_passphrase(short)? {
+        Some(hash) => hash,
+        None => return Ok(None)
     };
-    r.write_html11(s)
+    let given = match (&req.method, &req.body) {
+        (Method::Post, Some(RequestBody::FormUrlEncoded(map))) => map.get("passphrase"),
+        _ => None
+    };
+    let given = match given {
+        Some(pp) => pp,
+        None => return Ok(Some(protected_page(short, ResponseCode::Ok, "")))
+    };
+    // wrong passphrases count like wrong passwords, see `auth::authenticate`
+    let client = rate_limit_client(req, None);
+    if let Err(retry_after) = ratelimit::check(Action::FailedAuth, &client) {
+        return Ok(Some(too_many_requests(retry_after)));
+    }
+    if auth::verify_password_hash(given, &hash) {
+        ratelimit::refund(Action::FailedAuth, &client);
+        Ok(None)
+    } else {
+        Ok(Some(protected_page(short, ResponseCode::Forbidden, "Wrong passphrase")))
+    }
+}
+
+/// passphrase form of protected urls (dynamic)
+fn protected_page(short: &str, code: ResponseCode, error: &str) -> Response {
+    let mut page = std::fs::read_to_string("./page/dist/protected.html").unwrap();
+
+    page = page.replace("{{short-url}}", &html_escape(short));
+    page = page.replace("{{error}}", &html_escape(error));
+
+    let mut r = Response{
+        code,
+        custom_headers: None,
+        body: ResponseBody::Html(page)
+    };
+    r.set_header("Cache-Control", "no-store");
+    r
 }
 
 /// check if short url is free
-pub fn validate_short_url(short: &str, db: &dyn Database) -> bool {
+pub fn validate_short_url(short: &str, db: &dyn Database, settings: &Settings) -> bool {
     if short.len() < 3 { return false; }
-    if !RE_SHORT_URL_VALIDATE.as_ref().unwrap().is_match(&short) {
+    if !RE_SHORT_URL_VALIDATE.get().unwrap().is_match(short) {
         return false;
     }
 
-    if RESERVED_URLS.iter().any(|ru| ru.eq_ignore_ascii_case(&short)){
+    if RESERVED_URLS.iter().any(|ru| ru.eq_ignore_ascii_case(short)) ||
+        settings.reserved_words.iter().any(|ru| ru.eq_ignore_ascii_case(short)) {
         return false;
     }
-    db.peek_long_url(short).is_err()
+    match db.peek_long_url(short) {
+        Err(DbError::NotFound) => true,
+        // taken, or unknown if the database fails
+        _ => false
+    }
 }
 
 enum ValidationResult {
@@ -203,7 +676,7 @@ impl ValidationResult {
 /// validate long url, fail if html injection etc.
 // TODO better validation
 fn validate_long_url(long: &str) -> ValidationResult {
-    if long.len() < 3 || !RE_LONG_URL_VALIDATE.as_ref().unwrap().is_match(&long) {
+    if long.len() < 3 || !RE_LONG_URL_VALIDATE.get().unwrap().is_match(&long) {
         return ValidationResult::NoUrl;
     }
     ValidationResult::Ok
@@ -213,17 +686,18 @@ fn validate_long_url(long: &str) -> ValidationResult {
 /// format: /free?short=...
 pub fn free_check(req: &Request, db: &dyn Database) -> Result<Response, HandlerError> {
     //println!("{:?}", req.params);
+    rate_limit(Action::FreeCheck, &rate_limit_client(req, None)).map_err(HandlerError::Custom)?;
     // TODO check long urls
     let mut rcode = ResponseCode::NotAcceptable;
     let mut rbody = ResponseBody::Empty;
     if let Some(short) = req.params.get("short") {
-        if validate_short_url(short, db) {
+        if validate_short_url(short, db, &*Settings::cached(db)?) {
             rcode = ResponseCode::Ok;
             rbody = ResponseBody::Empty;
         }
     }
     if let Some(long) = req.params.get("long") {
-        match validate_long_url(long) {
+        match validate_long_url(&with_scheme(long)) {
             ValidationResult::Ok => {
                 rcode = ResponseCode::Ok;
                 rbody = ResponseBody::Empty;
@@ -269,3 +743,122 @@ pub fn static_content(req: &Request, _db: &dyn Database) -> Result<Response, Han
     };
     Ok(r)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn page(res: &Response) -> String {
+        String::from_utf8_lossy(res.body.get_bytes()).into_owned()
+    }
+
+    fn link(short: &str) -> NewLink<'_> {
+        NewLink {
+            short,
+            long: "https://example.org",
+            ip_hash: "test",
+            api_key: None,
+            expires_at: None,
+            max_redirects: None,
+            passphrase_hash: None,
+            redirect_code: None
+        }
+    }
+
+    fn store(db: &dyn Database, short: &str, long: &str) {
+        db.store_shortened(&NewLink { long, ..link(short) }).unwrap();
+    }
+
+    #[test]
+    fn escapes_the_status_page() {
+        let mut db = testutil::memory_db();
+        // stored before the long urls were validated
+        store(db.as_ref(), "xss", "https://x/\"><script>alert(1)</script>");
+        let res = crate::handle_request(&testutil::request("GET /status/xss HTTP/1.1\r\n\r\n"), db.as_mut());
+        let html = page(&res);
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(html.contains("href=\"https://x/&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;\""), "{}", html);
+    }
+
+    #[test]
+    fn escapes_the_not_found_page() {
+        let mut db = testutil::memory_db();
+        let req = testutil::request("GET /<img/src/onerror=alert(1)> HTTP/1.1\r\nAccept: text/html\r\n\r\n");
+        let html = page(&crate::handle_request(&req, db.as_mut()));
+        assert!(html.contains("The Url /&lt;img"), "{}", html);
+        assert!(!html.contains("<img"), "{}", html);
+    }
+
+    #[test]
+    fn only_accepts_http_urls() {
+        testutil::init();
+        assert_eq!(normalize_long_url("example.com/a?b=c").as_deref(), Some("https://example.com/a?b=c"));
+        assert_eq!(normalize_long_url("http://example.com").as_deref(), Some("http://example.com"));
+        assert!(normalize_long_url("x<script>alert(1)</script>").is_none());
+        assert!(normalize_long_url("https://x\"onmouseover=\"alert(1)").is_none());
+        assert!(normalize_long_url("https://x y").is_none());
+        assert!(normalize_long_url("https://").is_none());
+    }
+
+    #[test]
+    fn limits_ipv6_clients_by_prefix() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(rate_limit_ip(ip("2001:db8:1:2:aaaa::1")), "ip:2001:db8:1:2::/64");
+        assert_eq!(rate_limit_ip(ip("2001:db8:1:2:bbbb::2")), rate_limit_ip(ip("2001:db8:1:2::3")));
+        assert_ne!(rate_limit_ip(ip("2001:db8:1:3::1")), rate_limit_ip(ip("2001:db8:1:2::1")));
+        assert_eq!(rate_limit_ip(ip("192.0.2.1")), "ip:192.0.2.1");
+        assert_eq!(rate_limit_ip(ip("::ffff:192.0.2.1")), "ip:192.0.2.1");
+    }
+
+    /// a form post through the router
+    fn post_form(db: &mut dyn Database, path: &str, form: &str) -> Response {
+        let raw = format!("POST {} HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+                          path, form.len(), form);
+        crate::handle_request(&testutil::request(&raw), db)
+    }
+
+    #[test]
+    fn creates_and_deletes_with_forms() {
+        let mut db = testutil::memory_db();
+        let res = post_form(db.as_mut(), "/create", "long-url=example.org%2Fa&short-url=form&password=pw");
+        assert!(page(&res).contains("http://localhost/form"), "{}", page(&res));
+        let res = crate::handle_request(&testutil::request("GET /form HTTP/1.1\r\n\r\n"), db.as_mut());
+        assert_eq!(res.code as u16, 301);
+        assert_eq!(res.custom_headers.unwrap()["Location"], "https://example.org/a");
+
+        let res = post_form(db.as_mut(), "/create", "long-url=example.org&short-url=other&password=wrong");
+        assert_eq!(res.code as u16, 400);
+        assert_eq!(post_form(db.as_mut(), "/delete", "short-url=form&password=wrong").code as u16, 403);
+        assert_eq!(post_form(db.as_mut(), "/delete", "short-url=form&password=admin%3Asecret").code as u16, 200);
+        assert_eq!(post_form(db.as_mut(), "/delete", "short-url=form&password=pw").code as u16, 404);
+    }
+
+    #[test]
+    fn stores_no_setting_if_one_is_invalid() {
+        let mut db = testutil::memory_db();
+        let res = post_form(db.as_mut(), "/config", "short-url-length=8&reserved-words=a%2Cb&max-urls-per-week=0&password=pw");
+        assert_eq!(res.code as u16, 400);
+        assert_eq!(db.get_setting("short-url-length").unwrap(), None);
+        assert_eq!(db.get_setting("reserved-words").unwrap(), None);
+
+        let res = post_form(db.as_mut(), "/config", "short-url-length=8&max-urls-per-week=20&password=pw");
+        assert_eq!(res.code as u16, 200, "{}", page(&res));
+        let settings = Settings::load(db.as_ref()).unwrap();
+        assert_eq!((settings.short_url_length, settings.max_urls_per_week), (8, 20));
+    }
+
+    #[test]
+    fn only_visits_forward() {
+        let mut db = testutil::memory_db();
+        db.store_shortened(&NewLink { max_redirects: Some(1), ..link("once") }).unwrap();
+        for method in ["DELETE", "PATCH"] {
+            let res = crate::handle_request(&testutil::request(&format!("{} /once HTTP/1.1\r\n\r\n", method)), db.as_mut());
+            assert_eq!(res.code as u16, 405, "{}", method);
+            assert_eq!(res.custom_headers.unwrap()["Allow"], "GET, POST");
+        }
+        assert_eq!(db.url_stats("once").unwrap().redirects, 0);
+        let res = crate::handle_request(&testutil::request("GET /once HTTP/1.1\r\n\r\n"), db.as_mut());
+        assert_eq!(res.code as u16, 301);
+    }
+}
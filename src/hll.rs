@@ -0,0 +1,57 @@
+/// HyperLogLog sketch estimating the number of distinct hashes inserted into it (about 3% error).
+/// It only keeps the max rank per register, so the inserted hashes can't be recovered
+pub struct HyperLogLog {
+    registers: Vec<u8>
+}
+
+/// bits of the hash selecting the register
+const PRECISION: u32 = 10;
+
+/// size of a sketch, as stored in the database
+pub const REGISTERS: usize = 1 << PRECISION;
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog { registers: vec![0; REGISTERS] }
+    }
+
+    /// a stored sketch, empty if `bytes` has the wrong size
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        if bytes.len() != REGISTERS {
+            return Self::new();
+        }
+        HyperLogLog { registers: bytes.to_vec() }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// the register of `hash` and the rank (position of the first set bit of the remaining bits) to store in it,
+    /// for databases updating the registers themselves
+    pub fn position(hash: u64) -> (usize, u8) {
+        let idx = (hash >> (64 - PRECISION)) as usize;
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        (idx, rank)
+    }
+
+    pub fn insert(&mut self, hash: u64) {
+        let (idx, rank) = Self::position(hash);
+        self.registers[idx] = self.registers[idx].max(rank);
+    }
+
+    pub fn estimate(&self) -> u32 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // few distinct hashes: linear counting of the empty registers is more exact
+        let empty = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as u32
+        } else {
+            estimate.round() as u32
+        }
+    }
+}
@@ -1,126 +1,271 @@
 #![feature(osstring_ascii)]
 #![feature(result_flattening)]
 
-use std::net::{TcpListener, SocketAddr, TcpStream};
+use std::net::{TcpListener, SocketAddr};
 use chrono::prelude::*;
-use std::io::{ErrorKind, BufReader};
+use std::io::{BufRead, BufReader};
 use std::convert::TryFrom;
 
 mod request;
 use request::*;
-use crate::database::Database;
+use crate::database::{Database, DbError};
 use crate::handler::HandlerError;
-use crate::response::{Response, ResponseCode, ResponseBody};
-use std::collections::HashMap;
+use crate::response::{Response, ResponseCode};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 mod response;
 
 mod handler;
 
+mod api;
+
+mod auth;
+
+mod cli;
+
 mod database;
 
+mod config;
+use crate::config::Config;
+
+mod pool;
+
+mod sweeper;
+
+mod bots;
+
+mod hll;
+
+mod ratelimit;
+
+mod proxy;
+
+mod tls;
+use crate::tls::Stream;
+
+#[cfg(test)]
+mod testutil;
+
 pub(crate) fn log<T: AsRef<str>>(msg: T) {
     println!("[{:?}] {}", Local::now(), msg.as_ref());
 }
 
+fn limit_display(limit: Option<ratelimit::Limit>) -> String {
+    limit.map_or("off".into(), |l| l.to_string())
+}
+
 //const PORT: usize = 80; // production port
 pub static DEBUG_VERBOSE: AtomicBool = AtomicBool::new(false);
 
 fn main() -> Result<(), ()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     log("Started Shorty-rs");
 
-    let base_url = std::env::var("SHORTY_BASE_URL").expect("Set SHORTY_BASE_URL");
-    let database_path = std::env::var("SHORTY_DB_PATH").expect("Set SHORTY_DB_PATH");
-    let port: u16 = std::env::var("SHORTY_PORT").ok().map(|s| s.parse().ok()).flatten().unwrap_or(80);
-    DEBUG_VERBOSE.store(std::env::var("SHORTY_DEBUG").is_ok(), Ordering::Release);
+    let config = Config::from_env();
+    DEBUG_VERBOSE.store(config.debug, Ordering::Release);
 
     log(format!("Config from env-vars:\n\
      - Base address: {}\n\
      - Database:     {}\n\
      - Port:         {}\n\
-     - Debug:        {}",
-                base_url, database_path, port, DEBUG_VERBOSE.load(Ordering::Acquire)));
-
+     - Debug:        {}\n\
+     - Workers:      {}\n\
+     - Keep-alive:   {}s, at most {} idle\n\
+     - Timeouts:     read {}s, write {}s\n\
+     - Limits:       header {}B ({} lines), body {}B\n\
+     - Sweeper:      every {}s\n\
+     - Clicks:       {} sampled, kept {} days\n\
+     - Rate limits:  create {}, free {}, redirect {}, failed logins {}\n\
+     - Proxies:      {}\n\
+     - Https:        {}",
+                config.base_url, config.database_display(), config.port, config.debug, config.workers,
+                config.keep_alive_timeout, config.max_idle_connections, config.read_timeout, config.write_timeout,
+                config.max_header_size, config.max_headers, config.max_body_size, config.sweep_interval,
+                config.click_sample_rate, config.click_retention_days,
+                limit_display(config.rate_limit_create), limit_display(config.rate_limit_free),
+                limit_display(config.rate_limit_redirect), limit_display(config.rate_limit_auth),
+                if config.trusted_proxies.is_empty() { "none".into() } else {
+                    format!("{} ({})", config.trusted_proxies.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(", "),
+                            config.proxy_header.name())
+                },
+                if config.tls_enabled() {
+                    format!("port {}{}", config.tls_port, if config.https_redirect { ", redirecting http" } else { "" })
+                } else { "off".into() }));
 
+    let port = config.port;
+    let workers = config.workers;
+    let sweep_interval = Duration::from_secs(config.sweep_interval);
+    let click_retention_days = config.click_retention_days;
+    let database_path = config.database_path.clone();
+    config::init_config(config);
 
     request::init_regex();
 
     log("Starting listener");
     let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).unwrap();
+    let tls_listener = if config::config().tls_enabled() {
+        tls::reload().expect("Loading tls certificate failed");
+        tls::spawn_reloader().expect("Starting tls reloader failed");
+        Some(TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], config::config().tls_port))).unwrap())
+    } else {
+        None
+    };
+
+    log(format!("Starting {} workers, connecting to database", workers));
 
-    log("connecting to database");
+    let backend = database::Backend::from_path(&database_path);
+    if let database::Backend::Memory(db) = &backend {
+        log("Using in-memory database, all data is lost on exit");
+        // `<name>:<password>`, or unnamed like the passwords of old databases
+        for entry in std::env::var("SHORTY_MEMORY_PASSWORDS").unwrap_or_default().split(',').filter(|e| !e.is_empty()) {
+            let (name, pw) = match entry.split_once(':') {
+                Some((name, pw)) => (Some(name), pw),
+                None => (None, entry)
+            };
+            db.add_password(name, &auth::hash_password(pw)).expect("Adding password failed");
+        }
+    }
 
-    let mut db = database::SQLiteDB::init_database(&database_path)
+    let pool = pool::WorkerPool::new(workers, &backend)
         .expect("Database init failed");
 
+    let sweeper_db = backend.connect().expect("Database init failed");
+    sweeper::spawn(sweeper_db, sweep_interval, click_retention_days).expect("Starting sweeper failed");
 
     log(format!("Listening on port {:?}", listener.local_addr().unwrap().port()));
 
-    for stream in listener.incoming() {
-        if let Ok(s) = stream {
-            handle_request(BufReader::new(s), db.as_mut()).or_else(|e| {
-                log(format!("handling failed: {:?}", e));
-                Ok(())
-            })?;
+    std::thread::scope(|scope| {
+        if let Some(tls_listener) = tls_listener {
+            log(format!("Listening for https on port {:?}", tls_listener.local_addr().unwrap().port()));
+            let pool = &pool;
+            std::thread::Builder::new().name("tls-listener".into()).spawn_scoped(scope, move || {
+                for stream in tls_listener.incoming() {
+                    match stream.map_err(|e| e.to_string()).and_then(tls::accept) {
+                        Ok(s) => pool.dispatch(s),
+                        Err(e) => log(format!("accepting tls connection failed: {}", e))
+                    }
+                }
+            }).expect("Starting tls listener failed");
         }
-    }
+
+        for stream in listener.incoming() {
+            if let Ok(s) = stream {
+                pool.dispatch(Stream::Plain(s));
+            }
+        }
+    });
 
     Ok(())
 }
 
 
-fn handle_request(mut s: BufReader<TcpStream>, db: &mut dyn Database) -> std::io::Result<()> {
+/// how long a new connection may take for its first byte when there is no free `pool::IdleSlot`
+const FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(1);
 
-    let req = match Request::try_from(&mut s) {
-        Ok(r) => r,
-        Err(e) => { log(e); return Err(ErrorKind::InvalidData.into()); }
-    };
+/// Handles all requests of one connection until the client closes it,
+/// sends `Connection: close`, stays idle for longer than the keep-alive timeout or sends an invalid request.
+/// Connections are only kept alive while there is a free `pool::IdleSlot`
+fn handle_connection(mut s: BufReader<Stream>, db: &mut dyn Database) -> std::io::Result<()> {
+    s.get_ref().tcp().set_write_timeout(Some(Duration::from_secs(config::config().write_timeout)))?;
+    // waiting for the first request is idling as well
+    let mut idle = pool::IdleSlot::acquire();
+    if idle.is_none() {
+        s.get_ref().tcp().set_read_timeout(Some(FIRST_BYTE_TIMEOUT))?;
+        if !s.fill_buf().is_ok_and(|b| !b.is_empty()) {
+            return Ok(());
+        }
+    }
+
+    loop {
+        let next = Request::try_from(&mut s);
+        // the worker is busy again
+        drop(idle.take());
+        let req = match next {
+            Ok(r) => r,
+            Err(e) => {
+                // answer with the matching error code, the stream position is lost anyway
+                if let Some(mut res) = e.response() {
+                    log(format!("invalid request: {:?}", e));
+                    res.set_header("Connection", "close");
+                    res.write_html11(s.get_mut())?;
+                }
+                return Ok(());
+            }
+        };
+
+        // pipelined requests are read right away, waiting for new ones needs a slot
+        let keep_alive = req.keep_alive() && (!s.buffer().is_empty() || {
+            idle = pool::IdleSlot::acquire();
+            idle.is_some()
+        });
+        let mut res = if config::config().https_redirect && !s.get_ref().is_tls() {
+            tls::https_redirect(&req)
+        } else {
+            handle_request(&req, db)
+        };
+        res.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+        res.write_html11(s.get_mut())?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+
+fn handle_request(req: &Request, db: &mut dyn Database) -> Response {
 
     // routing
     for (_route_name, test, handle_fn) in &handler::HANDLERS {
-        if test(&req) {
+        if test(req) {
             //log(format!("Handling {} with {}", req.basic_info(), route_name));
             // handle_fn can either return the valid response, or diffrent error codes
-            return match handle_fn(&req, db) {
-                Ok(res) => {
-                    res.write_html11(s.get_mut())
-                }
+            return match handle_fn(req, db) {
+                Ok(res) => res,
                 Err(HandlerError::E400(emsg)) => {
                     log(&emsg);
-                    handler::send_gen_error_page(s.get_mut(), &emsg)
+                    handler::gen_error_page(&emsg)
                 },
-                Err(HandlerError::E404) => {
-                    handler::send_404_page(s.get_mut(), &req)
-                },
-                Err(HandlerError::Custom(r)) => {
-                    r.write_html11(s.get_mut())
-                }
+                Err(HandlerError::E404) => handler::not_found_page(req),
+                Err(HandlerError::Db(e)) => handler::db_error_page(e, req),
+                Err(HandlerError::Custom(r)) => r
             }
         }
     }
     if req.url.len() == 1 {
-        // short url routing
-        match db.forward(&req.url[0]) {
-            Ok(long_url) => {
-                // forward
-                let mut h = HashMap::new();
-                h.insert("Location".into(), long_url);
-
-                // force browser to use no-cache to allow counting of redirects
-                h.insert("Cache-Control".into(), "no-cache".into());
-                return Response {
-                    code: ResponseCode::MovedPermanently,
-                    custom_headers: Some(h),
-                    body: ResponseBody::Empty
-                }.write_html11(s.get_mut())
-            },
-            Err(()) => {}
+        // only visits count, POST is the passphrase form
+        if !matches!(req.method, Method::Get | Method::Post) {
+            let mut r = Response::text(ResponseCode::MethodNotAllowed, "Short urls only accept GET and POST");
+            r.set_header("Allow", "GET, POST");
+            return r;
+        }
+        // short url routing, protected urls ask for their passphrase first
+        if let Err(r) = handler::rate_limit(ratelimit::Action::Redirect, &handler::rate_limit_client(req, None)) {
+            return r;
+        }
+        match handler::passphrase_gate(req, db) {
+            Ok(Some(form)) => return form,
+            Ok(None) => {},
+            Err(e) => return handler::db_error_page(e, req)
+        }
+        match handler::forward_page(req, db) {
+            Ok(redirect) => return redirect,
+            Err(DbError::NotFound) => {},
+            Err(e) => return handler::db_error_page(e, req)
         }
 
     }
 
 
     println!("unknown req: {:?}", req.url);
-    handler::send_404_page(s.get_mut(), &req)
+    handler::not_found_page(req)
 }
@@ -0,0 +1,104 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::io::BufReader;
+use std::panic::AssertUnwindSafe;
+use crate::database::Backend;
+use crate::tls::Stream;
+use crate::{log, handle_connection};
+use crate::config::config;
+
+/// Fixed number of worker threads, each with its own database connection.
+/// Accepted streams are distributed over a shared channel.
+pub struct WorkerPool {
+    sender: mpsc::Sender<Stream>,
+    #[allow(dead_code)]
+    workers: Vec<JoinHandle<()>>
+}
+
+impl WorkerPool {
+    /// Starts `size` workers, fails if a worker can't open the database
+    pub fn new(size: usize, backend: &Backend) -> Result<Self, String> {
+        let (sender, receiver) = mpsc::channel::<Stream>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut workers = Vec::with_capacity(size);
+        for id in 0..size {
+            // open in the calling thread, so a broken database is noticed at startup
+            let mut db = backend.connect().map_err(|e| e.to_string())?;
+            let receiver = Arc::clone(&receiver);
+
+            let handle = std::thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || loop {
+                    // lock is released before the request is handled
+                    let next = receiver.lock().unwrap().recv();
+                    let stream = match next {
+                        Ok(s) => s,
+                        // listener is gone
+                        Err(_) => break
+                    };
+
+                    // a panicking handler should only drop its connection, not the worker
+                    let res = std::panic::catch_unwind(AssertUnwindSafe(||
+                        handle_connection(BufReader::new(stream), db.as_mut())));
+                    match res {
+                        Ok(Err(e)) => log(format!("handling failed: {:?}", e)),
+                        Err(_) => log(format!("worker-{} panicked while handling request", id)),
+                        Ok(Ok(())) => {}
+                    }
+                })
+                .map_err(|e| e.to_string())?;
+            workers.push(handle);
+        }
+
+        Ok(WorkerPool { sender, workers })
+    }
+
+    /// Queues the stream for the next free worker
+    pub fn dispatch(&self, stream: Stream) {
+        if self.sender.send(stream).is_err() {
+            log("all workers stopped, dropping connection");
+        }
+    }
+}
+
+/// number of workers waiting on an idle keep-alive connection
+static IDLE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allows a worker to wait for the next request of a keep-alive connection. There are at most
+/// `max_idle_connections` of them, so idle clients can't block every worker. Released when dropped
+pub struct IdleSlot(());
+
+impl IdleSlot {
+    /// `None` if too many workers wait already, the connection should be closed then
+    pub fn acquire() -> Option<IdleSlot> {
+        let max = config().max_idle_connections;
+        IDLE_CONNECTIONS.fetch_update(Ordering::AcqRel, Ordering::Acquire, |idle| if idle < max { Some(idle + 1) } else { None })
+            .ok()
+            .map(|_| IdleSlot(()))
+    }
+}
+
+impl Drop for IdleSlot {
+    fn drop(&mut self) {
+        IDLE_CONNECTIONS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn limits_idle_connections() {
+        testutil::init();
+        let max = config().max_idle_connections;
+        assert!(max < config().workers);
+        let slots: Vec<IdleSlot> = (0..max).map(|_| IdleSlot::acquire().expect("free slot")).collect();
+        assert!(IdleSlot::acquire().is_none());
+        drop(slots);
+        assert!(IdleSlot::acquire().is_some());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::request::Request;
+use std::fmt;
+use std::net::IpAddr;
+
+/// An ip range like `10.0.0.0/8` or `fd00::/8`, a plain ip is a range of one
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let s = s.trim();
+        let (addr, prefix) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None)
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("{} is no ip range", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|p| *p <= max).ok_or_else(|| format!("{} has an invalid prefix", s))?,
+            None => max
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) =>
+                self.prefix == 0 || (u32::from(net) ^ u32::from(ip)) >> (32 - self.prefix as u32) == 0,
+            (IpAddr::V6(net), IpAddr::V6(ip)) =>
+                self.prefix == 0 || (u128::from(net) ^ u128::from(ip)) >> (128 - self.prefix as u32) == 0,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// one hop of `Forwarded: for=...` or `X-Forwarded-For`, which may be quoted and carry a port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        // `[2001:db8::1]:4711`
+        return rest[..rest.find(']')?].parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        // `192.0.2.60:4711`
+        let (host, _port) = node.split_once(':')?;
+        host.parse().ok()
+    })
+}
+
+/// The header the trusted proxies put the client ip into. Only this one is read,
+/// a client could send any of the others itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// `Forwarded: for=...` (RFC 7239)
+    Forwarded,
+    XForwardedFor,
+    XRealIp
+}
+
+impl ProxyHeader {
+    pub fn parse(s: &str) -> Result<ProxyHeader, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "forwarded" => Ok(ProxyHeader::Forwarded),
+            "x-forwarded-for" => Ok(ProxyHeader::XForwardedFor),
+            "x-real-ip" => Ok(ProxyHeader::XRealIp),
+            _ => Err(format!("{} is no proxy header, use Forwarded, X-Forwarded-For or X-Real-IP", s))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProxyHeader::Forwarded => "Forwarded",
+            ProxyHeader::XForwardedFor => "X-Forwarded-For",
+            ProxyHeader::XRealIp => "X-Real-IP"
+        }
+    }
+}
+
+/// the client ips of the proxy header, the one closest to the client first
+fn forwarded_chain(req: &Request, header: ProxyHeader) -> Option<Vec<Option<IpAddr>>> {
+    let value = req.header(header.name())?;
+    Some(match header {
+        // `for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"`
+        ProxyHeader::Forwarded => value.split(',')
+            .map(|hop| hop.split(';')
+                .find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    if key.trim().eq_ignore_ascii_case("for") { Some(value) } else { None }
+                })
+                .and_then(parse_node))
+            .collect(),
+        ProxyHeader::XForwardedFor => value.split(',').map(parse_node).collect(),
+        ProxyHeader::XRealIp => vec![parse_node(value)]
+    })
+}
+
+/// The ip of the client. If the connection comes from a `trusted` proxy, its `header` is followed
+/// from the right as long as the hops are trusted proxies as well. Unknown or invalid hops stop the search
+pub fn client_ip(req: &Request, trusted: &[Cidr], header: ProxyHeader) -> IpAddr {
+    let mut ip = req.ip;
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|c| c.contains(ip));
+    if !is_trusted(&ip) {
+        return ip;
+    }
+    if let Some(chain) = forwarded_chain(req, header) {
+        for hop in chain.into_iter().rev() {
+            match hop {
+                Some(hop) => ip = hop,
+                None => break
+            }
+            if !is_trusted(&ip) {
+                break;
+            }
+        }
+    }
+    ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|c| Cidr::parse(c).unwrap()).collect()
+    }
+
+    /// the client ip of a request from `peer` with the extra header lines `headers`
+    fn client(peer: &str, headers: &str, trusted: &[&str], header: ProxyHeader) -> IpAddr {
+        let mut req = testutil::request(&format!("GET /abc HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers));
+        req.ip = peer.parse().unwrap();
+        client_ip(&req, &cidrs(trusted), header)
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(Cidr::parse("10.0.0.0/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(Cidr::parse(" 127.0.0.1 ").unwrap().to_string(), "127.0.0.1/32");
+        assert_eq!(Cidr::parse("fd00::/8").unwrap().to_string(), "fd00::/8");
+        assert_eq!(Cidr::parse("::1").unwrap().to_string(), "::1/128");
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/").is_err());
+        assert!(Cidr::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn matches_cidrs() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(&"10.1.255.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+        // ipv4 clients of a dual stack socket
+        assert!(net.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&"1.2.3.4".parse().unwrap()));
+
+        let net = Cidr::parse("fd00::/8").unwrap();
+        assert!(net.contains(&"fd12:3456::1".parse().unwrap()));
+        assert!(!net.contains(&"fe80::1".parse().unwrap()));
+        assert!(Cidr::parse("::/0").unwrap().contains(&"2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ignores_headers_of_untrusted_peers() {
+        let ip = client("203.0.113.9", "X-Forwarded-For: 1.1.1.1\r\n", &["127.0.0.1"], ProxyHeader::XForwardedFor);
+        assert_eq!(ip, "203.0.113.9".parse::<IpAddr>().unwrap());
+        let ip = client("127.0.0.1", "X-Forwarded-For: 1.1.1.1\r\n", &[], ProxyHeader::XForwardedFor);
+        assert_eq!(ip, "127.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn follows_the_chain_from_the_right() {
+        let trusted = ["127.0.0.1", "10.0.0.0/8"];
+        // the client prepended a fake entry, the proxies appended the real one
+        let ip = client("127.0.0.1", "X-Forwarded-For: 6.6.6.6, 198.51.100.7, 10.0.0.2\r\n", &trusted,
+                        ProxyHeader::XForwardedFor);
+        assert_eq!(ip, "198.51.100.7".parse::<IpAddr>().unwrap());
+
+        let ip = client("127.0.0.1", "Forwarded: for=6.6.6.6, for=\"[2001:db8:cafe::17]:4711\";proto=https\r\n",
+                        &trusted, ProxyHeader::Forwarded);
+        assert_eq!(ip, "2001:db8:cafe::17".parse::<IpAddr>().unwrap());
+
+        let ip = client("127.0.0.1", "X-Real-IP: 192.0.2.60\r\n", &trusted, ProxyHeader::XRealIp);
+        assert_eq!(ip, "192.0.2.60".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn stops_at_invalid_hops() {
+        let ip = client("127.0.0.1", "X-Forwarded-For: 1.1.1.1, unknown, 10.0.0.2\r\n", &["127.0.0.1", "10.0.0.0/8"],
+                        ProxyHeader::XForwardedFor);
+        assert_eq!(ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn only_reads_the_configured_header() {
+        // nginx sets X-Forwarded-For and passes the Forwarded header of the client through
+        let headers = "Forwarded: for=6.6.6.6\r\nX-Real-IP: 7.7.7.7\r\nX-Forwarded-For: 198.51.100.7\r\n";
+        let ip = client("127.0.0.1", headers, &["127.0.0.1"], ProxyHeader::XForwardedFor);
+        assert_eq!(ip, "198.51.100.7".parse::<IpAddr>().unwrap());
+
+        // the proxy did not set its header, so there is no client ip but the peer's
+        let ip = client("127.0.0.1", "Forwarded: for=6.6.6.6\r\n", &["127.0.0.1"], ProxyHeader::XForwardedFor);
+        assert_eq!(ip, "127.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn parses_proxy_headers() {
+        assert_eq!(ProxyHeader::parse("x-forwarded-for"), Ok(ProxyHeader::XForwardedFor));
+        assert_eq!(ProxyHeader::parse("Forwarded"), Ok(ProxyHeader::Forwarded));
+        assert_eq!(ProxyHeader::parse("X-REAL-IP"), Ok(ProxyHeader::XRealIp));
+        assert!(ProxyHeader::parse("Via").is_err());
+    }
+}
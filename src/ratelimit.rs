@@ -0,0 +1,182 @@
+use crate::config::config;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+
+/// What a rate limit applies to, every action has its own buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// creating short urls with the form or the api
+    Create,
+    /// `/free?short=...`
+    FreeCheck,
+    /// visiting short urls, including their passphrase form
+    Redirect,
+    /// wrong passwords, api keys and passphrases, see `auth::authenticate`
+    FailedAuth
+}
+
+/// A bucket holds up to `burst` requests and refills `per_minute` of them every minute
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub per_minute: f64,
+    pub burst: f64
+}
+
+impl Limit {
+    /// `<per minute>[,<burst>]`, the burst defaults to the requests per minute. `None` for `0` (no limit)
+    pub fn parse(s: &str) -> Result<Option<Limit>, String> {
+        let mut parts = s.splitn(2, ',');
+        let per_minute: f64 = parts.next().unwrap_or_default().trim().parse()
+            .map_err(|_| format!("{} is no rate limit like 60 or 60,10", s))?;
+        let burst: f64 = match parts.next() {
+            Some(b) => b.trim().parse().map_err(|_| format!("{} is no rate limit like 60 or 60,10", s))?,
+            None => per_minute
+        };
+        if per_minute == 0.0 {
+            return Ok(None);
+        }
+        if !(per_minute > 0.0 && burst >= 1.0) {
+            return Err(format!("Rate limit {} must be positive with a burst of at least 1", s));
+        }
+        Ok(Some(Limit { per_minute, burst }))
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/min (burst {})", self.per_minute, self.burst)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * limit.per_minute / 60.0).min(limit.burst);
+        self.updated = now;
+    }
+}
+
+/// at most this many buckets are kept, the least recently used ones are dropped first
+const MAX_BUCKETS: usize = 10_000;
+
+/// how many are dropped at once, so finding the oldest ones is rare
+const EVICTED_BUCKETS: usize = MAX_BUCKETS / 10;
+
+#[derive(Default)]
+struct Buckets(HashMap<(Action, String), Bucket>);
+
+impl Buckets {
+    fn take(&mut self, action: Action, client: &str, limit: Limit, now: Instant) -> Result<(), u64> {
+        let key = (action, client.to_owned());
+        if self.0.len() >= MAX_BUCKETS && !self.0.contains_key(&key) {
+            self.evict();
+        }
+
+        let bucket = self.0.entry(key)
+            .or_insert(Bucket { tokens: limit.burst, updated: now });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) * 60.0 / limit.per_minute).ceil() as u64)
+        }
+    }
+
+    /// drops the `EVICTED_BUCKETS` least recently used buckets, only called with `MAX_BUCKETS` of them
+    fn evict(&mut self) {
+        let mut by_age: Vec<_> = self.0.iter().map(|(key, b)| (b.updated, key.clone())).collect();
+        by_age.select_nth_unstable_by_key(EVICTED_BUCKETS - 1, |(updated, _)| *updated);
+        for (_, key) in &by_age[..EVICTED_BUCKETS] {
+            self.0.remove(key);
+        }
+    }
+
+    fn refund(&mut self, action: Action, client: &str, limit: Limit, now: Instant) {
+        if let Some(bucket) = self.0.get_mut(&(action, client.to_owned())) {
+            bucket.refill(limit, now);
+            bucket.tokens = (bucket.tokens + 1.0).min(limit.burst);
+        }
+    }
+}
+
+/// In-memory token buckets by action and client, shared by all workers of this process
+static BUCKETS: OnceLock<Mutex<Buckets>> = OnceLock::new();
+
+fn limit(action: Action) -> Option<Limit> {
+    match action {
+        Action::Create => config().rate_limit_create,
+        Action::FreeCheck => config().rate_limit_free,
+        Action::Redirect => config().rate_limit_redirect,
+        Action::FailedAuth => config().rate_limit_auth
+    }
+}
+
+fn buckets() -> MutexGuard<'static, Buckets> {
+    // a panicking worker can't leave a half updated bucket
+    BUCKETS.get_or_init(Mutex::default).lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Takes one request from the bucket of `client` (e.g. `ip:127.0.0.1` or `key:3`).
+/// Fails with the seconds until the next request is allowed
+pub fn check(action: Action, client: &str) -> Result<(), u64> {
+    match limit(action) {
+        Some(limit) => buckets().take(action, client, limit, Instant::now()),
+        None => Ok(())
+    }
+}
+
+/// Gives back the request `check` took, for actions that only count when they fail
+pub fn refund(action: Action, client: &str) {
+    if let Some(limit) = limit(action) {
+        buckets().refund(action, client, limit, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LIMIT: Limit = Limit { per_minute: 60.0, burst: 2.0 };
+
+    #[test]
+    fn refunds_requests() {
+        let mut buckets = Buckets::default();
+        let now = Instant::now();
+        assert_eq!(buckets.take(Action::FailedAuth, "ip:a", LIMIT, now), Ok(()));
+        buckets.refund(Action::FailedAuth, "ip:a", LIMIT, now);
+        assert_eq!(buckets.take(Action::FailedAuth, "ip:a", LIMIT, now), Ok(()));
+        assert_eq!(buckets.take(Action::FailedAuth, "ip:a", LIMIT, now), Ok(()));
+        assert_eq!(buckets.take(Action::FailedAuth, "ip:a", LIMIT, now), Err(1));
+        // other clients and actions have their own buckets
+        assert_eq!(buckets.take(Action::FailedAuth, "ip:b", LIMIT, now), Ok(()));
+        assert_eq!(buckets.take(Action::Create, "ip:a", LIMIT, now), Ok(()));
+        assert_eq!(buckets.take(Action::FailedAuth, "ip:a", LIMIT, now + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_buckets() {
+        let mut buckets = Buckets::default();
+        let start = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            buckets.take(Action::Redirect, &format!("ip:{}", i), LIMIT, start + Duration::from_millis(i as u64)).unwrap();
+        }
+        let now = start + Duration::from_secs(60);
+        buckets.take(Action::Redirect, "ip:0", LIMIT, now).unwrap();
+        assert_eq!(buckets.0.len(), MAX_BUCKETS);
+
+        buckets.take(Action::Redirect, "ip:new", LIMIT, now).unwrap();
+        assert_eq!(buckets.0.len(), MAX_BUCKETS - EVICTED_BUCKETS + 1);
+        assert!(buckets.0.contains_key(&(Action::Redirect, "ip:0".to_string())));
+        assert!(!buckets.0.contains_key(&(Action::Redirect, "ip:1".to_string())));
+        assert!(!buckets.0.contains_key(&(Action::Redirect, format!("ip:{}", EVICTED_BUCKETS))));
+        assert!(buckets.0.contains_key(&(Action::Redirect, format!("ip:{}", EVICTED_BUCKETS + 1))));
+    }
+}
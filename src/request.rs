@@ -2,26 +2,30 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use regex::Regex;
 use crate::{log, DEBUG_VERBOSE};
-use std::io::{BufReader, BufRead, Read};
-use std::net::{TcpStream, IpAddr};
+use crate::config::config;
+use crate::proxy;
+use crate::tls::Stream;
+use crate::response::{Response, ResponseCode};
+use std::io::{BufReader, BufRead, Read, ErrorKind};
+use std::time::{Duration, Instant};
+use std::net::IpAddr;
 use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
 
-pub static RE_GET_HEADER: Option<Regex> = None;
-pub static RE_SHORT_URL_VALIDATE: Option<Regex> = None;
-pub static RE_LONG_URL_VALIDATE: Option<Regex> = None;
+pub static RE_GET_HEADER: OnceLock<Regex> = OnceLock::new();
+pub static RE_SHORT_URL_VALIDATE: OnceLock<Regex> = OnceLock::new();
+pub static RE_LONG_URL_VALIDATE: OnceLock<Regex> = OnceLock::new();
 
 
-// TODO change to safe variant
+/// compiles the regexes once, they are shared by all worker threads
 pub(crate) fn init_regex() {
 
     log("Init regex");
 
-    unsafe {
-        *(&RE_GET_HEADER as *const _ as *mut _) = Some(Regex::new(r"(GET|POST) /([^?\s]*/?)*(?:\?(\S+=\S+)+)? HTTP/1\.1").unwrap());
-        *(&RE_SHORT_URL_VALIDATE as *const _ as *mut _) = Some(Regex::new(r"^[\w\d|\-|_]{3,}$").unwrap());
-        // TODO valid url regex
-        *(&RE_LONG_URL_VALIDATE as *const _ as *mut _) = Some(Regex::new(r"[^<>]{3,}").unwrap());
-    }
+    let _ = RE_GET_HEADER.set(Regex::new(r"(GET|POST|PATCH|DELETE) /([^?\s]*/?)*(?:\?(\S+=\S+)+)? HTTP/1\.1").unwrap());
+    let _ = RE_SHORT_URL_VALIDATE.set(Regex::new(r"^[\w\d|\-|_]{3,}$").unwrap());
+    // TODO valid url regex
+    let _ = RE_LONG_URL_VALIDATE.set(Regex::new(r#"^https?://[^\s<>"]+$"#).unwrap());
 }
 
 /// checks if c is a "hex char", eg. 0-9, a-f, A-F
@@ -56,7 +60,9 @@ pub fn decode_url_str(mut encoded: &str) -> Option<String> {
 #[derive(Debug, PartialEq)]
 pub enum Method {
     Get,
-    Post
+    Post,
+    Patch,
+    Delete
 }
 
 impl TryFrom<&str> for Method {
@@ -67,15 +73,20 @@ impl TryFrom<&str> for Method {
             Ok(Method::Get)
         } else if value.eq_ignore_ascii_case("post") {
             Ok(Method::Post)
+        } else if value.eq_ignore_ascii_case("patch") {
+            Ok(Method::Patch)
+        } else if value.eq_ignore_ascii_case("delete") {
+            Ok(Method::Delete)
         } else { Err(()) }
     }
 }
 
 /// The content if the client provided an request body
-/// Currently only accepting x-form-url-encoded
+/// Currently only accepting x-form-url-encoded and json
 #[derive(Debug)]
 pub enum RequestBody {
-    FormUrlEncoded(HashMap<String, String>)
+    FormUrlEncoded(HashMap<String, String>),
+    Json(serde_json::Value)
 }
 
 /// The request send from the client
@@ -83,6 +94,8 @@ pub enum RequestBody {
 pub struct Request {
     pub method: Method,
     pub url: Box<[String]>,
+    /// path and query as sent, e.g. `/abc?x=1`
+    pub target: String,
     pub params: HashMap<String, String>,
     pub headers: HashMap<String, String>,
     pub body: Option<RequestBody>,
@@ -96,35 +109,151 @@ impl Request {
     pub fn basic_info(&self) -> String {
         format!("Request {{ {:?} {} }}", self.method, self.url.join("/"))
     }
+
+    /// Header lookup ignoring the case of the name
+    pub fn header(&self, name: &str) -> Option<&String> {
+        find_header(&self.headers, name)
+    }
+
+    /// Http 1.1 connections are persistent, unless the client sends `Connection: close`
+    pub fn keep_alive(&self) -> bool {
+        !self.header("Connection").is_some_and(|c| c.eq_ignore_ascii_case("close"))
+    }
 }
 
 
-impl TryFrom<&mut BufReader<TcpStream>> for Request {
-    type Error = &'static str;
+fn find_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a String> {
+    headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+}
 
-    fn try_from(s: &mut BufReader<TcpStream>) -> Result<Self, Self::Error> {
-        let mut buffer = String::with_capacity(1024);
+/// the media type of a `Content-Type` header, without parameters like `charset`
+fn is_content_type(headers: &HashMap<String, String>, media_type: &str) -> bool {
+    find_header(headers, "Content-Type")
+        .is_some_and(|ct| ct.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(media_type))
+}
 
-        let debug = DEBUG_VERBOSE.load(Ordering::Relaxed);
+/// Why no request could be read from the connection
+#[derive(Debug)]
+pub enum RequestError {
+    /// the client closed the connection or sent nothing within the idle timeout
+    Closed,
+    /// the request was not completely received within the read timeout
+    Timeout,
+    /// the header is longer than `max_header_size` or has more than `max_headers` lines
+    HeaderTooLarge,
+    /// the announced body is longer than `max_body_size`
+    BodyTooLarge,
+    /// the body is sent with `Transfer-Encoding` (e.g. chunked) instead of a `Content-Length`
+    TransferEncoding(String),
+    Malformed(&'static str)
+}
+
+impl RequestError {
+    /// The response to send before closing the connection, `None` if the client is gone
+    pub fn response(&self) -> Option<Response> {
+        match self {
+            RequestError::Closed => None,
+            RequestError::Timeout => Some(Response::text(ResponseCode::RequestTimeout, "Request timed out")),
+            RequestError::HeaderTooLarge => Some(Response::text(ResponseCode::RequestHeaderFieldsTooLarge,
+                                                                "Request header too large")),
+            RequestError::BodyTooLarge => Some(Response::text(ResponseCode::PayloadTooLarge, "Request body too large")),
+            RequestError::TransferEncoding(encoding) if encoding.eq_ignore_ascii_case("chunked") =>
+                Some(Response::text(ResponseCode::LengthRequired, "Chunked bodies are not supported, send a Content-Length")),
+            RequestError::TransferEncoding(_) =>
+                Some(Response::text(ResponseCode::NotImplemented, "Transfer-Encoding is not supported")),
+            RequestError::Malformed(msg) => Some(Response::text(ResponseCode::BadRequest, msg))
+        }
+    }
+}
 
-        let linescan = s.read_line(&mut buffer);
+#[inline]
+fn is_timeout(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
 
-        if linescan.is_err() || linescan.unwrap() == 0 {
-            return Err("No http header");
+/// sets the socket read timeout to the time left until `deadline`
+fn set_deadline(s: &BufReader<Stream>, deadline: Instant) -> Result<(), RequestError> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.as_millis() == 0 {
+        return Err(RequestError::Timeout);
+    }
+    s.get_ref().tcp().set_read_timeout(Some(remaining)).map_err(|_| RequestError::Closed)
+}
+
+/// Appends one line (including the `\n`) to `buffer`, reading at most `limit` bytes.
+/// Returns the length of the line, 0 if the stream ended
+fn read_line_limited(s: &mut BufReader<Stream>, buffer: &mut Vec<u8>, limit: usize, deadline: Instant)
+    -> Result<usize, RequestError> {
+    let start = buffer.len();
+    loop {
+        set_deadline(s, deadline)?;
+        let available = match s.fill_buf() {
+            Ok(b) => b,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if is_timeout(&e) => return Err(RequestError::Timeout),
+            Err(_) => return Err(RequestError::Closed)
+        };
+        if available.is_empty() {
+            return Ok(buffer.len() - start);
+        }
+        let (used, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(idx) => (idx + 1, true),
+            None => (available.len(), false)
+        };
+        if buffer.len() - start + used > limit {
+            return Err(RequestError::HeaderTooLarge);
+        }
+        buffer.extend_from_slice(&available[..used]);
+        s.consume(used);
+        if done {
+            return Ok(buffer.len() - start);
+        }
+    }
+}
+
+
+impl TryFrom<&mut BufReader<Stream>> for Request {
+    type Error = RequestError;
+
+    fn try_from(s: &mut BufReader<Stream>) -> Result<Self, Self::Error> {
+        let conf = config();
+        let debug = DEBUG_VERBOSE.load(Ordering::Relaxed);
+
+        // wait for the next request, the read timeout starts with its first byte
+        if s.buffer().is_empty() {
+            s.get_ref().tcp().set_read_timeout(Some(Duration::from_secs(conf.keep_alive_timeout)))
+                .map_err(|_| RequestError::Closed)?;
+            match s.fill_buf() {
+                Ok(b) if !b.is_empty() => {},
+                _ => return Err(RequestError::Closed)
+            }
+        }
+        let deadline = Instant::now() + Duration::from_secs(conf.read_timeout);
+        // request line and headers share this budget
+        let mut header_budget = conf.max_header_size;
+
+        let mut raw_line = Vec::with_capacity(1024);
+        let l = read_line_limited(s, &mut raw_line, header_budget, deadline)?;
+        if l == 0 {
+            return Err(RequestError::Closed);
         }
+        header_budget -= l;
+        let buffer = std::str::from_utf8(&raw_line).map_err(|_| RequestError::Malformed("no http header"))?;
+
+        let target = buffer.split_whitespace().nth(1).unwrap_or("/").to_owned();
 
         // parse first line
         let (method, url, query): (Method, Vec<String>, HashMap<String, String>) = {
             //log(l);
-            let l = buffer.as_str();
+            let l = buffer;
             if debug { print!("{}", l); }
-            let matches = RE_GET_HEADER.as_ref().unwrap().captures(l).ok_or("no http header")?;
-            let method_match = matches.get(1).ok_or("no method match")?;
+            let matches = RE_GET_HEADER.get().unwrap().captures(l).ok_or(RequestError::Malformed("no http header"))?;
+            let method_match = matches.get(1).ok_or(RequestError::Malformed("no method match"))?;
             let url_match: Vec<String> = matches.get(2)
                 .map_or(vec![], |m|
                     m.as_str().split("/").map(|p| p.into()).collect()
                 );
-            let method = Method::try_from(method_match.as_str()).map_err(|_| "unknown method")?;
+            let method = Method::try_from(method_match.as_str()).map_err(|_| RequestError::Malformed("unknown method"))?;
             let mut q = HashMap::new();
 
             if let Some(qm) = matches.get(3) {
@@ -142,58 +271,156 @@ impl TryFrom<&mut BufReader<TcpStream>> for Request {
 
         // parse headers
         let mut headers = HashMap::new();
-        buffer.clear();
-        while let Ok(l) = s.read_line(&mut buffer) {
-            if l == 0 || buffer.trim().is_empty() { break }
-            if debug { println!("{}", buffer); }
-            if let Some(split_idx) = buffer.find(":") {
-                let name = (&buffer[0..split_idx]).trim();
-                let value = (&buffer[split_idx + 1 ..]).trim();
-                headers.insert(name.into(), value.into());
+        let mut header_count = 0;
+        loop {
+            raw_line.clear();
+            let l = read_line_limited(s, &mut raw_line, header_budget, deadline)?;
+            header_budget -= l;
+            let line = std::str::from_utf8(&raw_line).map_err(|_| RequestError::Malformed("header is no utf-8"))?;
+            if l == 0 || line.trim().is_empty() { break }
+            if debug { println!("{}", line); }
+            header_count += 1;
+            if header_count > conf.max_headers {
+                return Err(RequestError::HeaderTooLarge);
+            }
+            if let Some(split_idx) = line.find(":") {
+                let name = line[0..split_idx].trim();
+                let mut value: String = line[split_idx + 1 ..].trim().into();
+                // repeated headers are one comma separated list, whatever the case of their names
+                if let Some(key) = headers.keys().find(|k: &&String| k.eq_ignore_ascii_case(name)).cloned() {
+                    let previous = headers.remove(&key).unwrap_or_default();
+                    if name.eq_ignore_ascii_case("Content-Length") {
+                        if previous != value {
+                            return Err(RequestError::Malformed("conflicting Content-Length"));
+                        }
+                    } else {
+                        value = format!("{}, {}", previous, value);
+                    }
+                }
+                headers.insert(name.into(), value);
             }
-            buffer.clear();
         }
         let mut body = None;
 
-        // parse request body (form)
-        if method == Method::Post && headers.get("Content-Type").map_or(false,
-                    |ct: &String| ct.eq_ignore_ascii_case("application/x-www-form-urlencoded")) {
-            if let Ok(l) = headers.get("Content-Length").map_or(Ok(0usize), |s| s.parse::<usize>()) {
-
-                // read form str
-                let mut buffer = vec![0u8; l];
-                if s.read_exact(&mut buffer).is_ok() {
-                    if let Ok(body_str) = std::str::from_utf8(&buffer) {
-
-                        let mut payload = HashMap::new();
-                        for (key, val) in body_str.split("&").map(|u| {
-                            let sidx = u.find("=").unwrap_or(0);
-                            let k = decode_url_str(&u[0..sidx]).unwrap();
-                            let v = decode_url_str(&u[sidx + 1..]).unwrap();
-
-                            (k, v)
-                        }) {
-                            payload.insert(key, val);
-                        }
-                        body = Some(RequestBody::FormUrlEncoded(payload));
+        // the body can't be read without knowing where it ends, so the connection is closed
+        if let Some(encoding) = find_header(&headers, "Transfer-Encoding") {
+            let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+            return Err(RequestError::TransferEncoding(last.into()));
+        }
 
+        // read the whole body, so a following request on the same connection starts at the right position
+        let content_length = find_header(&headers, "Content-Length").map_or(Ok(0usize), |s| s.parse::<usize>())
+            .map_err(|_| RequestError::Malformed("invalid Content-Length"))?;
+        if content_length > conf.max_body_size {
+            return Err(RequestError::BodyTooLarge);
+        }
+        let mut raw_body = vec![0u8; content_length];
+        let mut filled = 0;
+        while filled < content_length {
+            set_deadline(s, deadline)?;
+            match s.read(&mut raw_body[filled..]) {
+                Ok(0) => return Err(RequestError::Malformed("incomplete body")),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) if is_timeout(&e) => return Err(RequestError::Timeout),
+                Err(_) => return Err(RequestError::Closed)
+            }
+        }
 
-                    }
+        // parse request body (form)
+        if method == Method::Post && is_content_type(&headers, "application/x-www-form-urlencoded") {
+            if let Ok(body_str) = std::str::from_utf8(&raw_body) {
+
+                let mut payload = HashMap::new();
+                for (key, val) in body_str.split("&").map(|u| {
+                    let sidx = u.find("=").unwrap_or(0);
+                    // forms encode spaces as `+`
+                    let k = decode_url_str(&u[0..sidx].replace('+', " ")).unwrap();
+                    let v = decode_url_str(&u[sidx + 1..].replace('+', " ")).unwrap();
+
+                    (k, v)
+                }) {
+                    payload.insert(key, val);
                 }
-
+                body = Some(RequestBody::FormUrlEncoded(payload));
             }
-
+        } else if content_length > 0 && is_content_type(&headers, "application/json") {
+            let value = serde_json::from_slice(&raw_body).map_err(|_| RequestError::Malformed("invalid json body"))?;
+            body = Some(RequestBody::Json(value));
         }
 
         //println!("{:?} {:?} {:?}", method, url, query);
 
-        Ok(Self {
+        let mut req = Self {
             method,
             url: url.into_boxed_slice(),
+            target,
             params: query,
             headers,
             body,
-            ip: s.get_ref().peer_addr().unwrap().ip()
-        })
+            ip: s.get_ref().tcp().peer_addr().map_err(|_| RequestError::Closed)?.ip()
+        };
+        req.ip = proxy::client_ip(&req, &conf.trusted_proxies, conf.proxy_header);
+        Ok(req)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn status(e: &RequestError) -> u16 {
+        e.response().map_or(0, |r| r.code as u16)
+    }
+
+    #[test]
+    fn reads_lowercase_body_headers() {
+        let body = r#"{"long":"https://example.com"}"#;
+        let raw = format!("POST /api/v1/links HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/json; charset=utf-8\r\n\
+                           content-length: {}\r\n\r\n{}GET /free?short=abc HTTP/1.1\r\n\r\n", body.len(), body);
+        let mut reqs = testutil::requests(&raw, 2).into_iter();
+        let req = reqs.next().unwrap().unwrap();
+        match &req.body {
+            Some(RequestBody::Json(v)) => assert_eq!(v["long"], "https://example.com"),
+            b => panic!("no json body: {:?}", b)
+        }
+        // the body was read completely, so the next request starts at the right position
+        let next = reqs.next().unwrap().unwrap();
+        assert_eq!(next.method, Method::Get);
+        assert_eq!(next.params.get("short").map(|s| s.as_str()), Some("abc"));
+    }
+
+    #[test]
+    fn reads_forms_with_charset() {
+        let body = "long-url=example.com&password=a+b%21";
+        let req = testutil::request(&format!("POST /create HTTP/1.1\r\nCONTENT-TYPE: application/x-www-form-urlencoded; charset=UTF-8\r\n\
+                                              Content-length: {}\r\n\r\n{}", body.len(), body));
+        match &req.body {
+            Some(RequestBody::FormUrlEncoded(map)) => assert_eq!(map.get("password").map(|s| s.as_str()), Some("a b!")),
+            b => panic!("no form body: {:?}", b)
+        }
+    }
+
+    #[test]
+    fn rejects_transfer_encodings() {
+        let raw = "POST /create HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let err = testutil::requests(raw, 1).pop().unwrap().unwrap_err();
+        assert_eq!(status(&err), 411);
+        let raw = "POST /create HTTP/1.1\r\nTransfer-Encoding: gzip\r\nContent-Length: 3\r\n\r\nabc";
+        let err = testutil::requests(raw, 1).pop().unwrap().unwrap_err();
+        assert_eq!(status(&err), 501);
+    }
+
+    #[test]
+    fn rejects_conflicting_lengths() {
+        let raw = "POST /create HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 4\r\n\r\nabcd";
+        let err = testutil::requests(raw, 1).pop().unwrap().unwrap_err();
+        assert_eq!(status(&err), 400);
+    }
+
+    #[test]
+    fn joins_repeated_headers() {
+        let req = testutil::request("GET /abc HTTP/1.1\r\nX-Forwarded-For: 1.1.1.1\r\nx-forwarded-for: 2.2.2.2\r\n\r\n");
+        assert_eq!(req.header("X-Forwarded-For").map(|s| s.as_str()), Some("1.1.1.1, 2.2.2.2"));
+    }
+}
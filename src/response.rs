@@ -1,4 +1,3 @@
-use std::net::TcpStream;
 use std::io::Write;
 use std::path::Path;
 use std::collections::HashMap;
@@ -11,20 +10,70 @@ use std::sync::atomic::Ordering;
 #[derive(Clone, Copy, Debug)]
 pub enum ResponseCode {
     Ok = 200,
+    Created = 201,
+    NoContent = 204,
     MovedPermanently = 301,
+    Found = 302,
+    SeeOther = 303,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
     BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
     NotFound = 404,
-    NotAcceptable = 406
+    MethodNotAllowed = 405,
+    NotAcceptable = 406,
+    RequestTimeout = 408,
+    Conflict = 409,
+    Gone = 410,
+    LengthRequired = 411,
+    PayloadTooLarge = 413,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    ServiceUnavailable = 503
 }
 
 impl ResponseCode {
     pub fn as_reason(&self) -> &'static str {
         match self {
             ResponseCode::Ok => "Ok",
+            ResponseCode::Created => "Created",
+            ResponseCode::NoContent => "No Content",
             ResponseCode::MovedPermanently => "Moved Permanently",
+            ResponseCode::Found => "Found",
+            ResponseCode::SeeOther => "See Other",
+            ResponseCode::TemporaryRedirect => "Temporary Redirect",
+            ResponseCode::PermanentRedirect => "Permanent Redirect",
             ResponseCode::BadRequest => "Bad Request",
+            ResponseCode::Unauthorized => "Unauthorized",
+            ResponseCode::Forbidden => "Forbidden",
             ResponseCode::NotFound => "Not Found",
-            ResponseCode::NotAcceptable => "Not Acceptable"
+            ResponseCode::MethodNotAllowed => "Method Not Allowed",
+            ResponseCode::NotAcceptable => "Not Acceptable",
+            ResponseCode::RequestTimeout => "Request Timeout",
+            ResponseCode::Conflict => "Conflict",
+            ResponseCode::Gone => "Gone",
+            ResponseCode::LengthRequired => "Length Required",
+            ResponseCode::PayloadTooLarge => "Payload Too Large",
+            ResponseCode::TooManyRequests => "Too Many Requests",
+            ResponseCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            ResponseCode::InternalServerError => "Internal Server Error",
+            ResponseCode::NotImplemented => "Not Implemented",
+            ResponseCode::ServiceUnavailable => "Service Unavailable"
+        }
+    }
+
+    /// the redirect with this status code, `None` if `code` is no redirect a short url may use
+    pub fn redirect(code: u16) -> Option<ResponseCode> {
+        match code {
+            301 => Some(ResponseCode::MovedPermanently),
+            302 => Some(ResponseCode::Found),
+            303 => Some(ResponseCode::SeeOther),
+            307 => Some(ResponseCode::TemporaryRedirect),
+            308 => Some(ResponseCode::PermanentRedirect),
+            _ => None
         }
     }
 }
@@ -35,6 +84,7 @@ pub enum ResponseBody {
     Html(String),
     CSS(String),
     JS(String),
+    Json(String),
     Custom{content_type: String, data: Box<[u8]>}
 }
 
@@ -75,6 +125,7 @@ impl ResponseBody {
             ResponseBody::Html(_) => "text/html;charset=UTF-8".into(),
             ResponseBody::CSS(_) => "text/css;charset=UTF-8".into(),
             ResponseBody::JS(_) => "text/javascript;charset=UTF-8".into(),
+            ResponseBody::Json(_) => "application/json;charset=UTF-8".into(),
             ResponseBody::Custom {content_type, ..} => content_type.clone()
         }
     }
@@ -83,7 +134,7 @@ impl ResponseBody {
     pub fn get_length(&self) -> usize {
         match self {
             ResponseBody::Empty => 0,
-            ResponseBody::Html(s) | ResponseBody::CSS(s) | ResponseBody::JS(s) => s.bytes().len(),
+            ResponseBody::Html(s) | ResponseBody::CSS(s) | ResponseBody::JS(s) | ResponseBody::Json(s) => s.bytes().len(),
             ResponseBody::Custom {data, ..} => data.len()
         }
     }
@@ -92,12 +143,47 @@ impl ResponseBody {
     pub fn get_bytes(&self) -> &[u8] {
         match self {
             ResponseBody::Empty => &[],
-            ResponseBody::Html(s) | ResponseBody::CSS(s) | ResponseBody::JS(s) => s.as_bytes(),
+            ResponseBody::Html(s) | ResponseBody::CSS(s) | ResponseBody::JS(s) | ResponseBody::Json(s) => s.as_bytes(),
             ResponseBody::Custom {data, ..} => &*data
         }
     }
 }
 
+/// Quotes and escapes `s` as a JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c)
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Escapes `s` for html text and quoted attribute values of the page templates
+pub fn html_escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c)
+        }
+    }
+    res
+}
+
 /// The response that can be send over TCP to the client
 #[derive(Debug)]
 pub struct Response {
@@ -108,27 +194,48 @@ pub struct Response {
 
 
 impl Response {
-    /// Write as http 1.1 to the TCP stream
+    /// Response with a `text/plain` body and no custom headers
+    pub fn text(code: ResponseCode, msg: &str) -> Self {
+        Response {
+            code,
+            custom_headers: None,
+            body: ResponseBody::Custom {
+                content_type: "text/plain".into(),
+                data: msg.bytes().collect()
+            }
+        }
+    }
+
+    /// Sets (or replaces) a header
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.custom_headers.get_or_insert_with(HashMap::new).insert(key.into(), value.into());
+    }
+
+    /// Write as http 1.1 to the TCP or TLS stream
     /// Extend here if you want to add support for Http2/3 etc
-    pub fn write_html11(self, s: &mut TcpStream) -> std::io::Result<()> {
+    pub fn write_html11<W: Write>(self, s: &mut W) -> std::io::Result<()> {
         //println!("Sending response {:?} ", self);
-        writeln!(s, "HTTP/1.1 {} {}", self.code as u16, self.code.as_reason())?;
+        // collect the head first, so it is send in one packet. http wants CRLF line endings
+        let mut head = Vec::with_capacity(256);
+        write!(head, "HTTP/1.1 {} {}\r\n", self.code as u16, self.code.as_reason())?;
         if DEBUG_VERBOSE.load(Ordering::Relaxed) { println!("Wrinting response:\n{:?}", &self) }
         // write custom headers
         if let Some(headers) = &self.custom_headers {
             for (key, val) in headers.iter() {
-                writeln!(s, "{}: {}", key, val)?;
+                write!(head, "{}: {}\r\n", key, val)?;
             }
         }
-        if self.body.is_empty() {
-            writeln!(s, "")?;
-            return Ok(())
+        if !self.body.is_empty() {
+            write!(head, "Content-Type: {}\r\n", self.body.get_content_type())?;
         }
-
-        writeln!(s, "Content-Type: {}", self.body.get_content_type())?;
-        writeln!(s, "Content-Length: {}", self.body.get_length())?;
-        writeln!(s, "")?;
+        // always needed on persistent connections, even without body (but forbidden for 204)
+        if !matches!(self.code, ResponseCode::NoContent) {
+            write!(head, "Content-Length: {}\r\n", self.body.get_length())?;
+        }
+        head.extend_from_slice(b"\r\n");
+        s.write_all(&head)?;
         // write body
-        s.write_all(self.body.get_bytes())
+        s.write_all(self.body.get_bytes())?;
+        s.flush()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,36 @@
+use crate::database::Database;
+use crate::log;
+use chrono::{Local, Duration as ChronoDuration};
+use std::time::Duration;
+
+/// Background thread with its own connection, which archives expired urls, clears legacy ip hashes
+/// and deletes clicks and daily visitor counts older than `click_retention_days` every `interval`
+pub fn spawn(db: Box<dyn Database>, interval: Duration, click_retention_days: u64) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .name("sweeper".into())
+        .spawn(move || loop {
+            match db.archive_expired() {
+                Ok(0) => {},
+                Ok(n) => log(format!("Archived {} expired urls", n)),
+                Err(e) => log(format!("Archiving expired urls failed: {}", e))
+            }
+            match db.clear_legacy_ip_hashes() {
+                Ok(0) => {},
+                Ok(n) => log(format!("Cleared {} legacy ip hashes", n)),
+                Err(e) => log(format!("Clearing legacy ip hashes failed: {}", e))
+            }
+            let since = Local::now().naive_local() - ChronoDuration::days(click_retention_days as i64);
+            match db.purge_clicks(&since.format("%Y-%m-%d %H:%M:%S").to_string()) {
+                Ok(0) => {},
+                Ok(n) => log(format!("Deleted {} old clicks", n)),
+                Err(e) => log(format!("Deleting old clicks failed: {}", e))
+            }
+            match db.purge_daily_visitors(&since.format("%Y-%m-%d").to_string()) {
+                Ok(0) => {},
+                Ok(n) => log(format!("Deleted {} old daily visitor counts", n)),
+                Err(e) => log(format!("Deleting old daily visitor counts failed: {}", e))
+            }
+            std::thread::sleep(interval);
+        })?;
+    Ok(())
+}
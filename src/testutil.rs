@@ -0,0 +1,52 @@
+use crate::config::{self, Config};
+use crate::database::{Backend, Database};
+use crate::request::{self, Request, RequestError};
+use crate::tls::Stream;
+use std::convert::TryFrom;
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Config and regexes for tests: in-memory database, no rate limits, no trusted proxies
+pub fn init() {
+    INIT.call_once(|| {
+        std::env::set_var("SHORTY_BASE_URL", "http://localhost");
+        std::env::set_var("SHORTY_DB_PATH", ":memory:");
+        std::env::set_var("SHORTY_IP_HASH_SECRET", "test secret");
+        std::env::set_var("SHORTY_WORKERS", "4");
+        for limit in ["SHORTY_RATE_LIMIT_CREATE", "SHORTY_RATE_LIMIT_FREE", "SHORTY_RATE_LIMIT_REDIRECT",
+                      "SHORTY_RATE_LIMIT_AUTH"] {
+            std::env::set_var(limit, "0");
+        }
+        std::env::remove_var("SHORTY_TRUSTED_PROXIES");
+        config::init_config(Config::from_env());
+        request::init_regex();
+    });
+}
+
+/// a fresh in-memory database with the unnamed password `pw` and `admin:secret`
+pub fn memory_db() -> Box<dyn Database> {
+    init();
+    let db = Backend::from_path(":memory:").connect().expect("memory database");
+    db.add_password(None, &crate::auth::hash_password("pw")).unwrap();
+    db.add_password(Some("admin"), &crate::auth::hash_password("secret")).unwrap();
+    db
+}
+
+/// Parses `raw` with the real request parser, sent over a local connection (so the peer is `127.0.0.1`)
+pub fn request(raw: &str) -> Request {
+    requests(raw, 1).pop().unwrap().expect("valid request")
+}
+
+/// Parses `count` requests sent at once on one connection, like a pipelining client
+pub fn requests(raw: &str, count: usize) -> Vec<Result<Request, RequestError>> {
+    init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client.write_all(raw.as_bytes()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let mut reader = BufReader::new(Stream::Plain(server));
+    (0..count).map(|_| Request::try_from(&mut reader)).collect()
+}
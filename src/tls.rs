@@ -0,0 +1,208 @@
+use crate::config::config;
+use crate::log;
+use crate::request::Request;
+use crate::response::{Response, ResponseCode};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock};
+
+/// The certificate of new connections, replaced by `reload`. Running connections keep their config
+static SERVER_CONFIG: RwLock<Option<Arc<ServerConfig>>> = RwLock::new(None);
+
+/// A connection of the plain http or the https listener
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>)
+}
+
+impl Stream {
+    /// the underlying socket, for timeouts and the peer address
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            Stream::Tls(s) => &s.sock
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            // the handshake happens with the first read, limited by the read timeout of the socket
+            Stream::Tls(s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush()
+        }
+    }
+}
+
+impl Drop for Stream {
+    /// tells the client the response is complete, without waiting for its answer
+    fn drop(&mut self) {
+        if let Stream::Tls(s) = self {
+            s.conn.send_close_notify();
+            while s.conn.wants_write() {
+                if s.conn.write_tls(&mut s.sock).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// reads the certificate chain and private key from the pem files
+fn load(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Reading certificate {} failed: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Reading private key {} failed: {}", key_path, e))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+    Ok(Arc::new(config))
+}
+
+/// (Re)loads `SHORTY_TLS_CERT` and `SHORTY_TLS_KEY`. On errors the previous certificate stays in use
+pub fn reload() -> Result<(), String> {
+    let conf = config();
+    let (cert, key) = match (&conf.tls_cert, &conf.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Err("Tls is not configured".into())
+    };
+    install(load(cert, key)?);
+    Ok(())
+}
+
+/// the certificate of connections accepted from now on
+fn install(server_config: Arc<ServerConfig>) {
+    *SERVER_CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(server_config);
+}
+
+/// Background thread reloading the certificate on SIGHUP, e.g. after it was renewed
+pub fn spawn_reloader() -> std::io::Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+    std::thread::Builder::new()
+        .name("tls-reloader".into())
+        .spawn(move || {
+            for _ in signals.forever() {
+                match reload() {
+                    Ok(()) => log("Reloaded tls certificate"),
+                    Err(e) => log(format!("Reloading tls certificate failed, keeping the old one: {}", e))
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Wraps an accepted connection of the https listener, the handshake is done by the worker
+pub fn accept(tcp: TcpStream) -> Result<Stream, String> {
+    let server_config = SERVER_CONFIG.read().unwrap_or_else(|e| e.into_inner()).clone()
+        .ok_or("Tls certificate not loaded")?;
+    let conn = ServerConnection::new(server_config).map_err(|e| e.to_string())?;
+    Ok(Stream::Tls(Box::new(StreamOwned::new(conn, tcp))))
+}
+
+/// Permanent redirect of a plain http request to the same target on the https port.
+/// The host is the one of `SHORTY_BASE_URL`, a `Host` header could send visitors anywhere
+pub fn https_redirect(req: &Request) -> Response {
+    let conf = config();
+    let base = conf.base_url.split_once("://").map_or(&conf.base_url[..], |(_, rest)| rest);
+    let host = base.split('/').next().unwrap_or_default();
+    // strip the port of `host:80`, but not the colons of `[::1]`
+    let host = match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host
+    };
+    let location = match conf.tls_port {
+        443 => format!("https://{}{}", host, req.target),
+        port => format!("https://{}:{}{}", host, port, req.target)
+    };
+    let mut res = Response::text(ResponseCode::PermanentRedirect, "Use https");
+    res.set_header("Location", &location);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryFrom;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+
+    #[test]
+    fn serves_https() {
+        testutil::init();
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("shorty-tls-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        install(load(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut db = testutil::memory_db();
+            let (tcp, _) = listener.accept().unwrap();
+            crate::handle_connection(BufReader::new(accept(tcp).unwrap()), db.as_mut()).unwrap();
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let conn = ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut client = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        client.write_all(b"GET /missing HTTP/1.1\r\nHost: localhost\r\nAccept: text/html\r\nConnection: close\r\n\r\n").unwrap();
+        let mut res = String::new();
+        client.read_to_string(&mut res).unwrap();
+        server.join().unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"), "{:?}", res);
+        assert!(res.contains("\r\nConnection: close\r\n"), "{:?}", res);
+        assert!(res.contains("The Url /missing"), "{}", res);
+    }
+
+    #[test]
+    fn redirects_to_the_base_url() {
+        testutil::init();
+        let req = testutil::request("GET /abc?x=1 HTTP/1.1\r\nHost: evil.example\r\n\r\n");
+        let res = https_redirect(&req);
+        let location = res.custom_headers.as_ref().and_then(|h| h.get("Location")).unwrap();
+        let expected = match config().tls_port {
+            443 => "https://localhost/abc?x=1".to_string(),
+            port => format!("https://localhost:{}/abc?x=1", port)
+        };
+        assert_eq!(*location, expected);
+    }
+}